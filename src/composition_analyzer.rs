@@ -0,0 +1,260 @@
+// 出力したローマ字が IME の中でつながって別の仮名になっていないかを調べる。
+// 同時押しの出力は 1 つずつ IME に打ち込まれるため、前の出力の末尾と次の出力の先頭が
+// 1 つの綴りとして解釈されることがある (ん を n と綴った後に あ が続くと な になる、など)。
+// ローマ字の表を逆引きした変換器で、出力のすべての組を続けて打った結果を確かめる。
+
+use std::collections::{HashMap, HashSet};
+
+use crate::diagnostics::Diagnostic;
+use crate::keycode_mapping::{normalize_kana, parse_to_input_string};
+use crate::mapping_sections::is_section_header;
+use crate::mapping_source::Mapping;
+use crate::romaji_scheme::{katakana_to_hiragana, RomajiScheme};
+
+// ローマ字から仮名への変換器。表の綴りに加え、IME が共通して持つ規則
+// (子音の重ねで っ、母音・y・n 以外の前の n で ん) を再現する。
+pub struct RomajiConverter {
+    table: HashMap<String, String>,
+    // 表にある綴りの真の先頭部分。入力がこれに一致する間は次の文字を待つ。
+    prefixes: HashSet<String>,
+}
+
+impl RomajiConverter {
+    pub fn new(romaji: &RomajiScheme) -> RomajiConverter {
+        let mut table = HashMap::new();
+        let mut prefixes = HashSet::new();
+        for (kana, spelling) in romaji.entries() {
+            table.insert(spelling.to_string(), kana.to_string());
+            for (index, _) in spelling.char_indices().skip(1) {
+                prefixes.insert(spelling[..index].to_string());
+            }
+        }
+        RomajiConverter { table, prefixes }
+    }
+
+    pub fn convert(&self, typed: &str) -> String {
+        let mut converted = String::new();
+        let mut pending = String::new();
+        for c in typed.chars() {
+            pending.push(c);
+            self.resolve(&mut pending, &mut converted, false);
+        }
+        self.resolve(&mut pending, &mut converted, true);
+        converted
+    }
+
+    // 確定できる部分を converted に移す。finish なら入力の終わりとして残りをすべて確定する。
+    fn resolve(&self, pending: &mut String, converted: &mut String, finish: bool) {
+        while !pending.is_empty() {
+            if !finish && self.prefixes.contains(pending.as_str()) {
+                return;
+            }
+            if let Some(kana) = self.table.get(pending.as_str()) {
+                converted.push_str(kana);
+                pending.clear();
+                return;
+            }
+            // 先頭から一致する最も長い綴りがあれば確定する
+            let longest = pending
+                .char_indices()
+                .skip(1)
+                .map(|(index, _)| index)
+                .filter(|&index| self.table.contains_key(&pending[..index]))
+                .last();
+            if let Some(index) = longest {
+                converted.push_str(&self.table[&pending[..index]]);
+                pending.drain(..index);
+                continue;
+            }
+            let mut chars = pending.chars();
+            let first = chars.next().unwrap_or_default();
+            let second = chars.next();
+            let is_consonant = |c: char| c.is_ascii_lowercase() && !"aiueo".contains(c);
+            if first == 'n' && second.is_none_or(|c| is_consonant(c) && c != 'y' && c != 'n') {
+                converted.push('ん');
+            } else if second == Some(first) && is_consonant(first) && first != 'n' {
+                converted.push('っ');
+            } else {
+                converted.push(first);
+            }
+            pending.drain(..first.len_utf8());
+        }
+    }
+}
+
+// マッピングの出力のうち、ローマ字で打たれる仮名の出力
+struct KanaOutput<'a> {
+    mapping: &'a Mapping,
+    kana: String,
+    typed: String,
+}
+
+// 仮名の出力の綴り。接頭辞のない出力は全体で 1 つの綴りとして、text: は仮名ごとに綴る。
+fn spell_output(to: &str, romaji: &RomajiScheme) -> Option<(String, String)> {
+    let (text, whole) = match to.strip_prefix("text:") {
+        Some(text) => (text.trim().trim_matches('"'), false),
+        None => (to, true),
+    };
+    let kana = normalize_kana(text);
+    if kana.is_empty() || kana.chars().any(|c| c.is_ascii()) {
+        return None;
+    }
+    if whole {
+        return romaji
+            .romaji(&kana)
+            .map(|typed| (katakana_to_hiragana(&kana), typed.to_string()));
+    }
+    let chars: Vec<char> = kana.chars().collect();
+    let mut typed = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if i + 1 < chars.len() {
+            let pair: String = chars[i..i + 2].iter().collect();
+            if let Some(spelling) = romaji.romaji(&pair) {
+                typed.push_str(spelling);
+                i += 2;
+                continue;
+            }
+        }
+        typed.push_str(romaji.romaji(&chars[i].to_string())?);
+        i += 1;
+    }
+    Some((katakana_to_hiragana(&kana), typed))
+}
+
+fn kana_outputs<'a>(mappings: &'a [Mapping], romaji: &RomajiScheme) -> Vec<KanaOutput<'a>> {
+    let mut seen = HashSet::new();
+    let mut outputs = Vec::new();
+    for mapping in mappings.iter().filter(|m| !is_section_header(m)) {
+        let parsed = parse_to_input_string(&mapping.to);
+        for to in [parsed.to, parsed.to_if_alone, parsed.to_if_held_down]
+            .into_iter()
+            .flatten()
+        {
+            if let Some((kana, typed)) = spell_output(&to, romaji) {
+                if seen.insert(typed.clone()) {
+                    outputs.push(KanaOutput {
+                        mapping,
+                        kana,
+                        typed,
+                    });
+                }
+            }
+        }
+    }
+    outputs
+}
+
+// 出力を単独で打った場合と、2 つ続けて打った場合のすべての組で、意図した仮名にならないものを報告する
+pub fn analyze_composition(mappings: &[Mapping], romaji: &RomajiScheme) -> Vec<Diagnostic> {
+    let converter = RomajiConverter::new(romaji);
+    let outputs = kana_outputs(mappings, romaji);
+    let mut diagnostics = Vec::new();
+
+    for output in &outputs {
+        let converted = converter.convert(&output.typed);
+        if converted != output.kana {
+            diagnostics.push(Diagnostic::warning(
+                format!(
+                    "Output '{}' typed as '{}' converts to '{}'",
+                    output.kana, output.typed, converted
+                ),
+                output.mapping.location.clone(),
+            ));
+        }
+    }
+
+    for first in &outputs {
+        for second in &outputs {
+            let typed = format!("{}{}", first.typed, second.typed);
+            let expected = format!("{}{}", first.kana, second.kana);
+            // 単独でも変換できない出力は上で報告済み
+            let standalone = format!(
+                "{}{}",
+                converter.convert(&first.typed),
+                converter.convert(&second.typed)
+            );
+            let converted = converter.convert(&typed);
+            if converted != expected && standalone == expected {
+                diagnostics.push(Diagnostic::warning(
+                    format!(
+                        "'{}' ({}) followed by '{}' ({}) is typed as '{}' and converts to '{}' instead of '{}'",
+                        first.kana,
+                        first.mapping.from,
+                        second.kana,
+                        second.mapping.from,
+                        typed,
+                        converted,
+                        expected
+                    ),
+                    first.mapping.location.clone(),
+                ));
+            }
+        }
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::romaji_scheme::RomajiPreset;
+
+    fn mapping(from: &str, to: &str) -> Mapping {
+        Mapping {
+            from: from.to_string(),
+            to: to.to_string(),
+            applications: Default::default(),
+            location: None,
+        }
+    }
+
+    fn converter() -> RomajiConverter {
+        RomajiConverter::new(&RomajiScheme::preset(RomajiPreset::Hepburn))
+    }
+
+    #[test]
+    fn n_becomes_n_before_consonants_and_at_the_end() {
+        let converter = converter();
+        assert_eq!(converter.convert("kan"), "かん");
+        assert_eq!(converter.convert("kanka"), "かんか");
+        assert_eq!(converter.convert("kana"), "かな");
+        assert_eq!(converter.convert("kanya"), "かにゃ");
+        assert_eq!(converter.convert("konnyaku"), "こんやく");
+    }
+
+    #[test]
+    fn doubled_consonants_become_sokuon() {
+        let converter = converter();
+        assert_eq!(converter.convert("kitte"), "きって");
+        assert_eq!(converter.convert("zasshi"), "ざっし");
+        assert_eq!(converter.convert("xtsu"), "っ");
+    }
+
+    #[test]
+    fn pairs_that_merge_into_another_kana_are_reported() {
+        let mut romaji = RomajiScheme::preset(RomajiPreset::Hepburn);
+        romaji.insert("ん", "n");
+        let mappings = [mapping("j", "ん"), mapping("k", "あ")];
+        let messages: Vec<String> = analyze_composition(&mappings, &romaji)
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect();
+        assert_eq!(
+            messages,
+            ["'ん' (j) followed by 'あ' (k) is typed as 'na' and converts to 'な' instead of 'んあ'"]
+        );
+    }
+
+    #[test]
+    fn pairs_that_can_be_split_are_not_reported() {
+        let romaji = RomajiScheme::preset(RomajiPreset::Hepburn);
+        let mappings = [
+            mapping("j", "ん"),
+            mapping("k", "あ"),
+            mapping("l", "っ"),
+            mapping("semicolon", "text:かん"),
+        ];
+        assert!(analyze_composition(&mappings, &romaji).is_empty());
+    }
+}
@@ -1,5 +1,5 @@
 pub const MAPPINGS: &[(&str, &str)] = &[
-    ("japanese_eisuu", "left_control"),
-    ("japanese_kana", "left_shift"),
+    ("japanese_eisuu", "left_control tap(japanese_eisuu)"),
+    ("japanese_kana", "left_shift tap(japanese_kana)"),
     ("left_control", "japanese_kana"),
 ];
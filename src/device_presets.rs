@@ -0,0 +1,195 @@
+// 名前付きのデバイス定義 (JSON)。キーが名前、値が device_if / device_unless に渡す identifiers。
+//
+//   {
+//     "macbook": { "identifiers": [{ "is_built_in_keyboard": true }] },
+//     "split_us": { "identifiers": [{ "vendor_id": 7504, "product_id": 24926 }] }
+//   }
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use serde::Deserialize;
+
+use crate::json_structures::{ConditionVariant, DeviceIdentifier};
+use crate::karabiner_config_generator::RuleVariant;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DevicePreset {
+    pub identifiers: Vec<DeviceIdentifier>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(transparent)]
+pub struct DevicePresets {
+    presets: BTreeMap<String, DevicePreset>,
+}
+
+#[derive(Debug)]
+pub enum DevicePresetError {
+    FileReadError(String),
+    JsonError(String),
+    UnknownPreset(String),
+}
+
+impl std::fmt::Display for DevicePresetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DevicePresetError::FileReadError(s) => write!(f, "File read error: {}", s),
+            DevicePresetError::JsonError(s) => write!(f, "Device presets error: {}", s),
+            DevicePresetError::UnknownPreset(name) => {
+                write!(f, "Device preset '{}' is not defined", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DevicePresetError {}
+
+pub fn load_device_presets(file_path: &str) -> Result<DevicePresets, DevicePresetError> {
+    let content = fs::read_to_string(file_path).map_err(|e| {
+        DevicePresetError::FileReadError(format!("Failed to read file {}: {}", file_path, e))
+    })?;
+    serde_json::from_str(&content)
+        .map_err(|e| DevicePresetError::JsonError(format!("Failed to parse {}: {}", file_path, e)))
+}
+
+impl DevicePresets {
+    pub fn get(&self, name: &str) -> Result<&DevicePreset, DevicePresetError> {
+        self.presets
+            .get(name)
+            .ok_or_else(|| DevicePresetError::UnknownPreset(name.to_string()))
+    }
+
+    fn identifiers(&self, names: &[String]) -> Result<Vec<DeviceIdentifier>, DevicePresetError> {
+        let mut identifiers = Vec::new();
+        for name in names {
+            identifiers.extend(self.get(name)?.identifiers.iter().cloned());
+        }
+        Ok(identifiers)
+    }
+
+    // ルール全体に付ける device_if / device_unless
+    pub fn conditions(
+        &self,
+        if_names: &[String],
+        unless_names: &[String],
+    ) -> Result<Vec<ConditionVariant>, DevicePresetError> {
+        let mut conditions = Vec::new();
+        if !if_names.is_empty() {
+            conditions.push(ConditionVariant::DeviceIf {
+                identifiers: self.identifiers(if_names)?,
+            });
+        }
+        if !unless_names.is_empty() {
+            conditions.push(ConditionVariant::DeviceUnless {
+                identifiers: self.identifiers(unless_names)?,
+            });
+        }
+        Ok(conditions)
+    }
+
+    // 指定したデバイスごとに 1 つずつ変種を作る。指定がなければ限定しない変種 1 つだけ。
+    pub fn variants(&self, names: &[String]) -> Result<Vec<RuleVariant>, DevicePresetError> {
+        if names.is_empty() {
+            return Ok(vec![RuleVariant::default()]);
+        }
+        names
+            .iter()
+            .map(|name| {
+                Ok(RuleVariant {
+                    labels: vec![name.clone()],
+                    conditions: vec![ConditionVariant::DeviceIf {
+                        identifiers: self.get(name)?.identifiers.clone(),
+                    }],
+                    host_layout: None,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn presets() -> DevicePresets {
+        serde_json::from_value(json!({
+            "macbook": { "identifiers": [{ "is_built_in_keyboard": true }] },
+            "split_us": { "identifiers": [{ "vendor_id": 7504, "product_id": 24926 }] }
+        }))
+        .unwrap()
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn conditions_use_the_preset_identifiers() {
+        let conditions = presets()
+            .conditions(&names(&["split_us"]), &names(&["macbook"]))
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(conditions).unwrap(),
+            json!([
+                {
+                    "type": "device_if",
+                    "identifiers": [{ "vendor_id": 7504, "product_id": 24926 }]
+                },
+                {
+                    "type": "device_unless",
+                    "identifiers": [{ "is_built_in_keyboard": true }]
+                }
+            ])
+        );
+        // 複数のプリセットは 1 つの条件の identifiers にまとめる
+        let conditions = presets()
+            .conditions(&names(&["macbook", "split_us"]), &[])
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(conditions).unwrap(),
+            json!([{
+                "type": "device_if",
+                "identifiers": [
+                    { "is_built_in_keyboard": true },
+                    { "vendor_id": 7504, "product_id": 24926 }
+                ]
+            }])
+        );
+    }
+
+    #[test]
+    fn variants_are_labelled_with_the_preset_name() {
+        let variants = presets()
+            .variants(&names(&["macbook", "split_us"]))
+            .unwrap();
+        let labels: Vec<&[String]> = variants.iter().map(|v| v.labels.as_slice()).collect();
+        assert_eq!(labels, [names(&["macbook"]), names(&["split_us"])]);
+        assert_eq!(
+            serde_json::to_value(&variants[1].conditions).unwrap(),
+            json!([{
+                "type": "device_if",
+                "identifiers": [{ "vendor_id": 7504, "product_id": 24926 }]
+            }])
+        );
+        assert_eq!(presets().variants(&[]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn unknown_presets_are_errors() {
+        let presets = presets();
+        for result in [
+            presets.conditions(&names(&["macbook", "keychron"]), &[]),
+            presets.conditions(&[], &names(&["keychron"])),
+            presets.variants(&names(&["keychron"])).map(|_| Vec::new()),
+        ] {
+            match result {
+                Err(error @ DevicePresetError::UnknownPreset(_)) => {
+                    assert_eq!(error.to_string(), "Device preset 'keychron' is not defined");
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs;
+use std::path::Path;
+
+// マッピングファイル中の位置。line は 1 始まり、column は 0 始まり (proc-macro2 と同じ)。
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl SourceLocation {
+    pub fn from_span(file: &Path, span: proc_macro2::Span) -> SourceLocation {
+        let start = span.start();
+        SourceLocation {
+            file: file.display().to_string(),
+            line: start.line,
+            column: start.column,
+        }
+    }
+
+    // TOML のようにバイトオフセットで位置が得られる場合に使う
+    pub fn from_byte_offset(file: &str, content: &str, offset: usize) -> SourceLocation {
+        let before = &content[..offset.min(content.len())];
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        SourceLocation {
+            file: file.to_string(),
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count(),
+        }
+    }
+}
+
+impl std::fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column + 1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub location: Option<SourceLocation>,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, location: Option<SourceLocation>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            location,
+        }
+    }
+
+    pub fn warning(message: impl Into<String>, location: Option<SourceLocation>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            message: message.into(),
+            location,
+        }
+    }
+
+    // rustc 風に該当行とキャレットを付けて整形する
+    pub fn render(&self) -> String {
+        let label = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let mut rendered = format!("{}: {}", label, self.message);
+        let Some(location) = &self.location else {
+            return rendered;
+        };
+        rendered.push_str(&format!("\n  --> {}", location));
+
+        let source_line = fs::read_to_string(&location.file)
+            .ok()
+            .and_then(|content| content.lines().nth(location.line - 1).map(String::from));
+        if let Some(source_line) = source_line {
+            let gutter = " ".repeat(location.line.to_string().len());
+            let indent: String = source_line
+                .chars()
+                .take(location.column)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            rendered.push_str(&format!(
+                "\n{gutter} |\n{} | {}\n{gutter} | {}^",
+                location.line, source_line, indent
+            ));
+        }
+        rendered
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: {}", location, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+pub fn render_diagnostics(diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(Diagnostic::render)
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
@@ -0,0 +1,1165 @@
+// 生成したルールに時刻付きのキー入力を与え、Karabiner が出力するキーを求める。
+// Mac に入れて打たなくても配列を確かめられるように、basic マニピュレーターの主な動作を再現する。
+//   - from.modifiers の mandatory / optional (左右を区別しない修飾キーを含む)
+//   - conditions (変数、アプリ、デバイス、キーボードの種類、入力ソース)
+//   - 同時押し (最初のキーから閾値内に残りのキーが押される) と simultaneous_options の
+//     detect_key_down_uninterruptedly / key_down_order / key_up_order / key_up_when / to_after_key_up
+//   - to (最後のキーだけ from を離すまで押し続ける)、to_if_alone、to_if_held_down、to_after_key_up
+//   - set_variable
+// アプリなどの条件の正規表現は regex クレートで解釈し、解釈できないものはエラーにする。
+
+use std::collections::{HashMap, HashSet};
+
+use regex::Regex;
+
+use crate::json_structures::{
+    ConditionVariant, DeviceIdentifier, File, KeyCode, KeyOrder, KeyUpWhen, Manipulator, Modifier,
+    ToEvent, VariableValue,
+};
+
+// Karabiner の既定値
+const SIMULTANEOUS_THRESHOLD_MILLISECONDS: u64 = 50;
+const TO_IF_ALONE_TIMEOUT_MILLISECONDS: u64 = 1000;
+const TO_IF_HELD_DOWN_THRESHOLD_MILLISECONDS: u64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyAction {
+    Down,
+    Up,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputEvent {
+    pub time: u64,
+    pub key_code: KeyCode,
+    pub action: KeyAction,
+}
+
+// 出力されたキー。modifiers はキーを押した時点で効いている修飾キー。
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputEvent {
+    pub time: u64,
+    pub key_code: KeyCode,
+    pub modifiers: Vec<Modifier>,
+    pub action: KeyAction,
+}
+
+impl std::fmt::Display for OutputEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let action = match self.action {
+            KeyAction::Down => "down",
+            KeyAction::Up => "up",
+        };
+        write!(f, "{} {} ", self.time, action)?;
+        for modifier in &self.modifiers {
+            write!(f, "{}+", modifier_name(*modifier))?;
+        }
+        write!(f, "{}", self.key_code)
+    }
+}
+
+// 同時押しの判定結果。split は、同時押しのキーが重なって押されたのに別々のキーとして扱われた回数。
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ChordStats {
+    pub detected: usize,
+    pub split: usize,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Simulation {
+    pub events: Vec<OutputEvent>,
+    pub chords: ChordStats,
+}
+
+// 条件の判定に使う、キー入力を受け取っている Mac の状態
+#[derive(Debug, Default, Clone)]
+pub struct SimulatorEnvironment {
+    pub bundle_identifier: Option<String>,
+    pub file_path: Option<String>,
+    pub input_source_id: Option<String>,
+    pub keyboard_type: Option<String>,
+    pub device: DeviceIdentifier,
+}
+
+// 押されているキーが今何をしているか
+#[derive(Debug)]
+enum Activity {
+    // どのマニピュレーターにも一致せず、そのまま出力している
+    PassThrough,
+    Manipulated(ActiveManipulator),
+    // 同時押しの 2 つ目以降のキー。出力は最初のキーの側で管理する。
+    Member(KeyCode),
+    // 同時押しの他のキーが先に離され、出力を終えている。値はマニピュレーターの位置。
+    Released(usize),
+}
+
+#[derive(Debug)]
+struct ActiveManipulator {
+    manipulator: usize,
+    keys: Vec<KeyCode>,
+    held: Option<(KeyCode, Vec<Modifier>)>,
+    modifiers: Vec<Modifier>,
+    down_time: u64,
+    interrupted: bool,
+    held_down_fired: bool,
+}
+
+struct Simulator<'a> {
+    manipulators: Vec<&'a Manipulator>,
+    environment: &'a SimulatorEnvironment,
+    variables: HashMap<String, VariableValue>,
+    held_modifiers: Vec<Modifier>,
+    pressed: HashMap<KeyCode, Activity>,
+    // 同時押しのマニピュレーターごとに、キーが離された順
+    released_orders: HashMap<usize, Vec<KeyCode>>,
+    output: Vec<OutputEvent>,
+    chords: ChordStats,
+    // conditions に書かれた正規表現をコンパイルしたもの
+    patterns: HashMap<String, Regex>,
+}
+
+pub fn simulate(
+    file: &File,
+    environment: &SimulatorEnvironment,
+    events: &[InputEvent],
+) -> Result<Simulation, String> {
+    let mut events = events.to_vec();
+    events.sort_by_key(|event| event.time);
+
+    let manipulators: Vec<&Manipulator> = file
+        .rules
+        .iter()
+        .filter(|rule| rule.enabled != Some(false))
+        .flat_map(|rule| rule.manipulators.iter())
+        .collect();
+    let patterns = compile_patterns(&manipulators)?;
+    let mut simulator = Simulator {
+        manipulators,
+        environment,
+        variables: HashMap::new(),
+        held_modifiers: Vec::new(),
+        pressed: HashMap::new(),
+        released_orders: HashMap::new(),
+        output: Vec::new(),
+        chords: ChordStats::default(),
+        patterns,
+    };
+
+    let mut consumed = HashSet::new();
+    for index in 0..events.len() {
+        let event = &events[index];
+        simulator.fire_held_down(event.time);
+        if consumed.contains(&index) {
+            continue;
+        }
+        match event.action {
+            KeyAction::Down => simulator.key_down(&events, index, &mut consumed),
+            KeyAction::Up => simulator.key_up(event),
+        }
+    }
+    // 入力の終わりで押されたままのキーも、閾値を過ぎれば to_if_held_down を出力する
+    simulator.fire_held_down(u64::MAX);
+    Ok(Simulation {
+        events: simulator.output,
+        chords: simulator.chords,
+    })
+}
+
+// conditions の正規表現 (bundle_identifiers、file_paths、input_source_id) をまとめてコンパイルする。
+// Karabiner (ECMAScript の正規表現) では使えても regex クレートが解釈できないもの
+// (後方参照や先読みなど) は、誤った結果を出さないようにエラーにする。
+fn compile_patterns(manipulators: &[&Manipulator]) -> Result<HashMap<String, Regex>, String> {
+    let mut patterns = HashMap::new();
+    for condition in manipulators
+        .iter()
+        .flat_map(|manipulator| manipulator.conditions.iter().flatten())
+    {
+        let sources: Vec<&String> = match condition {
+            ConditionVariant::FrontmostApplicationIf {
+                bundle_identifiers,
+                file_paths,
+            }
+            | ConditionVariant::FrontmostApplicationUnless {
+                bundle_identifiers,
+                file_paths,
+            } => bundle_identifiers.iter().chain(file_paths).collect(),
+            ConditionVariant::InputSourceIf { input_sources } => input_sources
+                .iter()
+                .filter_map(|source| source.input_source_id.as_ref())
+                .collect(),
+            _ => Vec::new(),
+        };
+        for pattern in sources {
+            if patterns.contains_key(pattern) {
+                continue;
+            }
+            let regex = Regex::new(pattern).map_err(|e| {
+                format!(
+                    "Unsupported regular expression '{}' in conditions: {}",
+                    pattern, e
+                )
+            })?;
+            patterns.insert(pattern.clone(), regex);
+        }
+    }
+    Ok(patterns)
+}
+
+impl Simulator<'_> {
+    fn key_down(&mut self, events: &[InputEvent], index: usize, consumed: &mut HashSet<usize>) {
+        let event = &events[index];
+        for activity in self.pressed.values_mut() {
+            if let Activity::Manipulated(active) = activity {
+                active.interrupted = true;
+            }
+        }
+
+        for (position, manipulator) in self.manipulators.iter().enumerate() {
+            if !self.conditions_satisfied(manipulator) {
+                continue;
+            }
+            let Some(used_modifiers) = self.match_modifiers(manipulator) else {
+                continue;
+            };
+            let keys = if manipulator.from.key_code.as_ref() == Some(&event.key_code) {
+                vec![event.key_code.clone()]
+            } else if let Some(simultaneous) = &manipulator.from.simultaneous {
+                let keys: Vec<KeyCode> = simultaneous.iter().map(|k| k.key_code.clone()).collect();
+                if !keys.contains(&event.key_code) {
+                    continue;
+                }
+                let threshold = manipulator
+                    .parameters
+                    .as_ref()
+                    .and_then(|p| p.simultaneous_threshold_milliseconds)
+                    .unwrap_or(SIMULTANEOUS_THRESHOLD_MILLISECONDS);
+                let order = manipulator
+                    .from
+                    .simultaneous_options
+                    .as_ref()
+                    .and_then(|options| options.key_down_order)
+                    .unwrap_or(KeyOrder::Insensitive);
+                let uninterrupted = manipulator
+                    .from
+                    .simultaneous_options
+                    .as_ref()
+                    .and_then(|options| options.detect_key_down_uninterruptedly)
+                    .unwrap_or(false);
+                match find_simultaneous(
+                    events,
+                    index,
+                    &keys,
+                    consumed,
+                    threshold,
+                    order,
+                    uninterrupted,
+                ) {
+                    Some(indices) => {
+                        consumed.extend(indices);
+                        self.chords.detected += 1;
+                        self.released_orders.insert(position, Vec::new());
+                        keys
+                    }
+                    None => continue,
+                }
+            } else {
+                continue;
+            };
+            if keys.len() == 1 && self.overlaps_chord(events, index, consumed) {
+                self.chords.split += 1;
+            }
+            self.start_manipulator(position, keys, used_modifiers, event.time);
+            return;
+        }
+        if self.overlaps_chord(events, index, consumed) {
+            self.chords.split += 1;
+        }
+
+        // どのマニピュレーターにも一致しなければそのまま出力する
+        self.emit(
+            event.time,
+            event.key_code.clone(),
+            self.held_modifiers.clone(),
+            KeyAction::Down,
+        );
+        if let Some(modifier) = modifier_for_key(&event.key_code) {
+            self.held_modifiers.push(modifier);
+        }
+        self.pressed
+            .insert(event.key_code.clone(), Activity::PassThrough);
+    }
+
+    fn key_up(&mut self, event: &InputEvent) {
+        match self.pressed.remove(&event.key_code) {
+            Some(Activity::PassThrough) | None => {
+                if let Some(modifier) = modifier_for_key(&event.key_code) {
+                    self.held_modifiers.retain(|held| *held != modifier);
+                }
+                let modifiers = self.held_modifiers.clone();
+                self.emit(event.time, event.key_code.clone(), modifiers, KeyAction::Up);
+            }
+            Some(Activity::Released(position)) => {
+                self.record_release(position, &event.key_code);
+                let remaining = self
+                    .pressed
+                    .values()
+                    .any(|activity| matches!(activity, Activity::Released(p) if *p == position));
+                if !remaining {
+                    self.after_all_keys_up(position, event.time);
+                }
+            }
+            Some(Activity::Member(leader)) => {
+                if let Some(Activity::Manipulated(active)) = self.pressed.remove(&leader) {
+                    self.release_simultaneous(active, &event.key_code, event.time);
+                }
+            }
+            Some(Activity::Manipulated(active)) => {
+                self.release_simultaneous(active, &event.key_code, event.time);
+            }
+        }
+    }
+
+    // events[index] のキーを押している間に、そのキーを含む同時押しの残りのキーがすべて押されたか
+    fn overlaps_chord(
+        &self,
+        events: &[InputEvent],
+        index: usize,
+        consumed: &HashSet<usize>,
+    ) -> bool {
+        let start = &events[index];
+        let later: Vec<&KeyCode> = events
+            .iter()
+            .enumerate()
+            .skip(index + 1)
+            .take_while(|(_, event)| {
+                !(event.key_code == start.key_code && event.action == KeyAction::Up)
+            })
+            .filter(|(position, event)| {
+                event.action == KeyAction::Down && !consumed.contains(position)
+            })
+            .map(|(_, event)| &event.key_code)
+            .collect();
+        self.manipulators.iter().any(|manipulator| {
+            let Some(simultaneous) = &manipulator.from.simultaneous else {
+                return false;
+            };
+            simultaneous.iter().any(|k| k.key_code == start.key_code)
+                && simultaneous
+                    .iter()
+                    .all(|k| k.key_code == start.key_code || later.contains(&&k.key_code))
+                && self.conditions_satisfied(manipulator)
+                && self.match_modifiers(manipulator).is_some()
+        })
+    }
+
+    // 同時押しは、どれか 1 つのキーを離した時点で出力を終える (key_up_when が all ならすべて離した時点)
+    fn release_simultaneous(
+        &mut self,
+        mut active: ActiveManipulator,
+        released: &KeyCode,
+        time: u64,
+    ) {
+        let position = active.manipulator;
+        self.record_release(position, released);
+        let key_up_when = self.manipulators[position]
+            .from
+            .simultaneous_options
+            .as_ref()
+            .and_then(|options| options.key_up_when);
+        active.keys.retain(|key| key != released);
+        if key_up_when == Some(KeyUpWhen::All) && !active.keys.is_empty() {
+            self.hold_keys(active);
+            return;
+        }
+        for key in &active.keys {
+            self.pressed
+                .insert(key.clone(), Activity::Released(position));
+        }
+        let all_released = active.keys.is_empty();
+        self.finish_manipulator(active, time);
+        if all_released {
+            self.after_all_keys_up(position, time);
+        }
+    }
+
+    fn record_release(&mut self, position: usize, released: &KeyCode) {
+        if let Some(order) = self.released_orders.get_mut(&position) {
+            order.push(released.clone());
+        }
+    }
+
+    // 同時押しのキーがすべて離された後の出力 (key_up_order の順に離された場合だけ)
+    fn after_all_keys_up(&mut self, position: usize, time: u64) {
+        let manipulator = self.manipulators[position];
+        let released = self.released_orders.remove(&position).unwrap_or_default();
+        if let Some(options) = &manipulator.from.simultaneous_options {
+            let defined = manipulator
+                .from
+                .simultaneous
+                .iter()
+                .flatten()
+                .map(|key| &key.key_code);
+            let in_order = match options.key_up_order.unwrap_or(KeyOrder::Insensitive) {
+                KeyOrder::Insensitive => true,
+                KeyOrder::Strict => defined.eq(released.iter()),
+                KeyOrder::StrictInverse => defined.rev().eq(released.iter()),
+            };
+            if !in_order {
+                return;
+            }
+            let modifiers = self.held_modifiers.clone();
+            for to in &options.to_after_key_up {
+                self.tap(to, &modifiers, time);
+            }
+        }
+    }
+
+    fn start_manipulator(
+        &mut self,
+        position: usize,
+        keys: Vec<KeyCode>,
+        used_modifiers: Vec<Modifier>,
+        time: u64,
+    ) {
+        let manipulator = self.manipulators[position];
+        let modifiers: Vec<Modifier> = self
+            .held_modifiers
+            .iter()
+            .filter(|modifier| !used_modifiers.contains(modifier))
+            .copied()
+            .collect();
+        let last_key = manipulator.to.iter().rposition(|to| to.key_code.is_some());
+        let mut held = None;
+        for (index, to) in manipulator.to.iter().enumerate() {
+            if Some(index) == last_key {
+                held = self.press(to, &modifiers, time);
+                // 修飾キーを押し続ける出力 (hold(left_shift) など) は以降のキーに効く
+                if let Some(modifier) = held.as_ref().and_then(|(key, _)| modifier_for_key(key)) {
+                    self.held_modifiers.push(modifier);
+                }
+            } else {
+                self.tap(to, &modifiers, time);
+            }
+        }
+        self.hold_keys(ActiveManipulator {
+            manipulator: position,
+            keys,
+            held,
+            modifiers,
+            down_time: time,
+            interrupted: false,
+            held_down_fired: false,
+        });
+    }
+
+    // 最初のキーに出力の状態を持たせ、残りのキーはその一員として記録する
+    fn hold_keys(&mut self, active: ActiveManipulator) {
+        let mut keys = active.keys.clone().into_iter();
+        if let Some(leader) = keys.next() {
+            for key in keys {
+                self.pressed.insert(key, Activity::Member(leader.clone()));
+            }
+            self.pressed.insert(leader, Activity::Manipulated(active));
+        }
+    }
+
+    fn finish_manipulator(&mut self, active: ActiveManipulator, time: u64) {
+        let manipulator = self.manipulators[active.manipulator];
+        if let Some((key_code, modifiers)) = active.held {
+            if let Some(modifier) = modifier_for_key(&key_code) {
+                self.held_modifiers.retain(|held| *held != modifier);
+            }
+            self.emit(time, key_code, modifiers, KeyAction::Up);
+        }
+        for to in &manipulator.to_after_key_up {
+            self.tap(to, &active.modifiers, time);
+        }
+        let timeout = manipulator
+            .parameters
+            .as_ref()
+            .and_then(|p| p.to_if_alone_timeout_milliseconds)
+            .unwrap_or(TO_IF_ALONE_TIMEOUT_MILLISECONDS);
+        if !active.interrupted && !active.held_down_fired && time - active.down_time < timeout {
+            for to in &manipulator.to_if_alone {
+                self.tap(to, &active.modifiers, time);
+            }
+        }
+    }
+
+    // 閾値より長く押され続けているキーの to_if_held_down を出力する
+    fn fire_held_down(&mut self, now: u64) {
+        let mut due = Vec::new();
+        for (key, activity) in &self.pressed {
+            if let Activity::Manipulated(active) = activity {
+                let manipulator = self.manipulators[active.manipulator];
+                let threshold = manipulator
+                    .parameters
+                    .as_ref()
+                    .and_then(|p| p.to_if_held_down_threshold_milliseconds)
+                    .unwrap_or(TO_IF_HELD_DOWN_THRESHOLD_MILLISECONDS);
+                let fire_time = active.down_time + threshold;
+                if !manipulator.to_if_held_down.is_empty()
+                    && !active.interrupted
+                    && !active.held_down_fired
+                    && fire_time <= now
+                {
+                    due.push((key.clone(), fire_time));
+                }
+            }
+        }
+        due.sort_by_key(|(_, fire_time)| *fire_time);
+        for (key, fire_time) in due {
+            let Some(Activity::Manipulated(active)) = self.pressed.get_mut(&key) else {
+                continue;
+            };
+            active.held_down_fired = true;
+            let manipulator = self.manipulators[active.manipulator];
+            let modifiers = active.modifiers.clone();
+            for to in &manipulator.to_if_held_down {
+                self.tap(to, &modifiers, fire_time);
+            }
+        }
+    }
+
+    // to のキーを押す。押したキーと修飾キーを返す。
+    fn press(
+        &mut self,
+        to: &ToEvent,
+        modifiers: &[Modifier],
+        time: u64,
+    ) -> Option<(KeyCode, Vec<Modifier>)> {
+        if let Some(set_variable) = &to.set_variable {
+            self.variables
+                .insert(set_variable.name.clone(), set_variable.value.clone());
+        }
+        let key_code = to.key_code.clone()?;
+        let mut modifiers = modifiers.to_vec();
+        for modifier in to.modifiers.iter().flatten() {
+            if !modifiers.contains(modifier) {
+                modifiers.push(*modifier);
+            }
+        }
+        self.emit(time, key_code.clone(), modifiers.clone(), KeyAction::Down);
+        Some((key_code, modifiers))
+    }
+
+    fn tap(&mut self, to: &ToEvent, modifiers: &[Modifier], time: u64) {
+        if let Some((key_code, modifiers)) = self.press(to, modifiers, time) {
+            self.emit(time, key_code, modifiers, KeyAction::Up);
+        }
+    }
+
+    fn emit(&mut self, time: u64, key_code: KeyCode, modifiers: Vec<Modifier>, action: KeyAction) {
+        self.output.push(OutputEvent {
+            time,
+            key_code,
+            modifiers,
+            action,
+        });
+    }
+
+    // mandatory をすべて満たし、残りの修飾キーが optional で許されていれば、mandatory に使った修飾キーを返す
+    fn match_modifiers(&self, manipulator: &Manipulator) -> Option<Vec<Modifier>> {
+        let (mandatory, optional) = match &manipulator.from.modifiers {
+            Some(modifiers) => (&modifiers.mandatory[..], &modifiers.optional[..]),
+            None => (&[][..], &[][..]),
+        };
+        let mut used = Vec::new();
+        for required in mandatory {
+            let held = self
+                .held_modifiers
+                .iter()
+                .find(|held| !used.contains(*held) && modifier_satisfies(*required, **held))?;
+            used.push(*held);
+        }
+        let extra_allowed = self
+            .held_modifiers
+            .iter()
+            .filter(|held| !used.contains(held))
+            .all(|held| {
+                optional
+                    .iter()
+                    .any(|allowed| *allowed == Modifier::Any || modifier_satisfies(*allowed, *held))
+            });
+        extra_allowed.then_some(used)
+    }
+
+    fn pattern_matches(&self, pattern: &str, value: &str) -> bool {
+        self.patterns
+            .get(pattern)
+            .is_some_and(|regex| regex.is_match(value))
+    }
+
+    fn frontmost_application_matches(
+        &self,
+        bundle_identifiers: &[String],
+        file_paths: &[String],
+    ) -> bool {
+        let matches_any = |patterns: &[String], value: &Option<String>| {
+            value
+                .as_ref()
+                .is_some_and(|value| patterns.iter().any(|p| self.pattern_matches(p, value)))
+        };
+        matches_any(bundle_identifiers, &self.environment.bundle_identifier)
+            || matches_any(file_paths, &self.environment.file_path)
+    }
+
+    fn conditions_satisfied(&self, manipulator: &Manipulator) -> bool {
+        manipulator
+            .conditions
+            .iter()
+            .flatten()
+            .all(|condition| self.condition_satisfied(condition))
+    }
+
+    fn condition_satisfied(&self, condition: &ConditionVariant) -> bool {
+        let environment = self.environment;
+        match condition {
+            ConditionVariant::VariableIf { name, value } => self.variable(name) == *value,
+            ConditionVariant::VariableUnless { name, value } => self.variable(name) != *value,
+            ConditionVariant::FrontmostApplicationIf {
+                bundle_identifiers,
+                file_paths,
+            } => self.frontmost_application_matches(bundle_identifiers, file_paths),
+            ConditionVariant::FrontmostApplicationUnless {
+                bundle_identifiers,
+                file_paths,
+            } => !self.frontmost_application_matches(bundle_identifiers, file_paths),
+            ConditionVariant::DeviceIf { identifiers } => identifiers
+                .iter()
+                .any(|identifier| device_matches(identifier, &environment.device)),
+            ConditionVariant::DeviceUnless { identifiers } => !identifiers
+                .iter()
+                .any(|identifier| device_matches(identifier, &environment.device)),
+            ConditionVariant::KeyboardTypeIf { keyboard_types } => environment
+                .keyboard_type
+                .as_ref()
+                .is_some_and(|keyboard_type| keyboard_types.contains(keyboard_type)),
+            ConditionVariant::InputSourceIf { input_sources } => {
+                input_sources.iter().any(|source| {
+                    match (&source.input_source_id, &environment.input_source_id) {
+                        (Some(pattern), Some(id)) => self.pattern_matches(pattern, id),
+                        (None, _) => true,
+                        (Some(_), None) => false,
+                    }
+                })
+            }
+        }
+    }
+
+    // 設定されていない変数は 0 として扱う
+    fn variable(&self, name: &str) -> VariableValue {
+        self.variables
+            .get(name)
+            .cloned()
+            .unwrap_or(VariableValue::Int(0))
+    }
+}
+
+// 記録したキー入力を閾値ごとに流し直し、同時押しとして判定された数と分かれた数を比べる。
+// ルール中のすべての同時押しの manipulator に、試す閾値を parameters として設定して動かす。
+pub fn tune_simultaneous_threshold(
+    file: &mut File,
+    environment: &SimulatorEnvironment,
+    events: &[InputEvent],
+    thresholds: impl IntoIterator<Item = u64>,
+) -> Result<Vec<(u64, ChordStats)>, String> {
+    thresholds
+        .into_iter()
+        .map(|threshold| {
+            for manipulator in file
+                .rules
+                .iter_mut()
+                .flat_map(|rule| &mut rule.manipulators)
+            {
+                if manipulator.from.simultaneous.is_some() {
+                    manipulator
+                        .parameters
+                        .get_or_insert_with(Default::default)
+                        .simultaneous_threshold_milliseconds = Some(threshold);
+                }
+            }
+            Ok((threshold, simulate(file, environment, events)?.chords))
+        })
+        .collect()
+}
+
+// 最初のキー (events[index]) から閾値内に同時押しの残りのキーが押されていれば、それらの位置を返す。
+// order が strict なら keys の順に、strict_inverse なら逆順に押されていなければならない。
+// uninterrupted (detect_key_down_uninterruptedly) なら、間に他のキーが押された時点で同時押しではない。
+// そうでなければ間に押された他のキーは読み飛ばし、同時押しの出力の後で処理される。
+fn find_simultaneous(
+    events: &[InputEvent],
+    index: usize,
+    keys: &[KeyCode],
+    consumed: &HashSet<usize>,
+    threshold: u64,
+    order: KeyOrder,
+    uninterrupted: bool,
+) -> Option<Vec<usize>> {
+    let start = &events[index];
+    let mut remaining: Vec<&KeyCode> = keys.iter().filter(|k| **k != start.key_code).collect();
+    let mut found = Vec::new();
+    for (position, event) in events.iter().enumerate().skip(index + 1) {
+        if remaining.is_empty() {
+            break;
+        }
+        if event.time > start.time + threshold {
+            return None;
+        }
+        if consumed.contains(&position) {
+            continue;
+        }
+        match event.action {
+            KeyAction::Down => match remaining.iter().position(|k| **k == event.key_code) {
+                Some(slot) => {
+                    remaining.remove(slot);
+                    found.push(position);
+                }
+                None if uninterrupted || keys.contains(&event.key_code) => return None,
+                None => {}
+            },
+            // 揃う前に離されたキーがあれば同時押しではない
+            KeyAction::Up if keys.contains(&event.key_code) => return None,
+            KeyAction::Up => {}
+        }
+    }
+    if !remaining.is_empty() {
+        return None;
+    }
+    let pressed: Vec<&KeyCode> = std::iter::once(&start.key_code)
+        .chain(found.iter().map(|&position| &events[position].key_code))
+        .collect();
+    let in_order = match order {
+        KeyOrder::Insensitive => true,
+        KeyOrder::Strict => pressed.iter().copied().eq(keys.iter()),
+        KeyOrder::StrictInverse => pressed.iter().copied().eq(keys.iter().rev()),
+    };
+    in_order.then_some(found)
+}
+
+fn modifier_for_key(key_code: &KeyCode) -> Option<Modifier> {
+    match Modifier::from_name(key_code.name())? {
+        Modifier::Any
+        | Modifier::Command
+        | Modifier::Control
+        | Modifier::Option
+        | Modifier::Shift => None,
+        modifier => Some(modifier),
+    }
+}
+
+// 左右を区別しない修飾キーはどちらの側でも一致する
+fn modifier_satisfies(required: Modifier, held: Modifier) -> bool {
+    match required {
+        Modifier::Command => matches!(held, Modifier::LeftCommand | Modifier::RightCommand),
+        Modifier::Control => matches!(held, Modifier::LeftControl | Modifier::RightControl),
+        Modifier::Option => matches!(held, Modifier::LeftOption | Modifier::RightOption),
+        Modifier::Shift => matches!(held, Modifier::LeftShift | Modifier::RightShift),
+        other => other == held,
+    }
+}
+
+fn modifier_name(modifier: Modifier) -> String {
+    serde_json::to_value(modifier)
+        .ok()
+        .and_then(|value| value.as_str().map(String::from))
+        .unwrap_or_default()
+}
+
+fn device_matches(identifier: &DeviceIdentifier, device: &DeviceIdentifier) -> bool {
+    fn field<T: PartialEq>(expected: &Option<T>, actual: &Option<T>) -> bool {
+        expected.is_none() || expected == actual
+    }
+    field(&identifier.vendor_id, &device.vendor_id)
+        && field(&identifier.product_id, &device.product_id)
+        && field(&identifier.location_id, &device.location_id)
+        && field(&identifier.is_keyboard, &device.is_keyboard)
+        && field(
+            &identifier.is_built_in_keyboard,
+            &device.is_built_in_keyboard,
+        )
+}
+
+// 入力の台本。1 行に「時刻(ミリ秒) down|up キー名」を書く。# 以降と空行は無視する。
+//   0 down k
+//   10 down q
+//   80 up k
+pub fn parse_event_script(content: &str) -> Result<Vec<InputEvent>, String> {
+    let mut events = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [time, action, key] = fields.as_slice() else {
+            return Err(format!(
+                "line {}: expected '<milliseconds> down|up <key_code>'",
+                index + 1
+            ));
+        };
+        let time = time.parse().map_err(|_| {
+            format!(
+                "line {}: '{}' is not a number of milliseconds",
+                index + 1,
+                time
+            )
+        })?;
+        let action = match *action {
+            "down" => KeyAction::Down,
+            "up" => KeyAction::Up,
+            other => {
+                return Err(format!(
+                    "line {}: expected down or up, found '{}'",
+                    index + 1,
+                    other
+                ))
+            }
+        };
+        events.push(InputEvent {
+            time,
+            key_code: KeyCode::from_name(key),
+            action,
+        });
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::karabiner_config_generator::{
+        generate_karabiner_config, generate_karabiner_rule, RuleOptions,
+    };
+    use crate::manifest::generate_from_manifest;
+    use crate::mapping_source::{load_mappings, Mapping};
+
+    fn data_path(name: &str) -> String {
+        format!("{}/src/data/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn rules_from_mappings(mappings: &[Mapping], options: &RuleOptions) -> File {
+        let rule = generate_karabiner_rule("test".to_string(), mappings, options);
+        generate_karabiner_config(None, vec![rule])
+    }
+
+    fn rules_from_data(name: &str, options: &RuleOptions) -> File {
+        let mappings = load_mappings(&data_path(name)).unwrap();
+        rules_from_mappings(&mappings, options)
+    }
+
+    fn inline_mappings(pairs: &[(&str, &str)]) -> Vec<Mapping> {
+        pairs
+            .iter()
+            .map(|(from, to)| Mapping {
+                from: from.to_string(),
+                to: to.to_string(),
+                applications: Default::default(),
+                location: None,
+            })
+            .collect()
+    }
+
+    fn run(file: &File, script: &str) -> Simulation {
+        let events = parse_event_script(script).unwrap();
+        simulate(file, &SimulatorEnvironment::default(), &events).unwrap()
+    }
+
+    fn lines(simulation: &Simulation) -> Vec<String> {
+        simulation.events.iter().map(ToString::to_string).collect()
+    }
+
+    // 押されたキーだけを修飾キー付きで並べる
+    fn typed(simulation: &Simulation) -> Vec<String> {
+        simulation
+            .events
+            .iter()
+            .filter(|event| event.action == KeyAction::Down)
+            .map(|event| {
+                let mut name = String::new();
+                for modifier in &event.modifiers {
+                    name.push_str(&modifier_name(*modifier));
+                    name.push('+');
+                }
+                name.push_str(event.key_code.name());
+                name
+            })
+            .collect()
+    }
+
+    #[test]
+    fn simultaneous_chord_types_its_output() {
+        let file = rules_from_data("shingeta.rs", &RuleOptions::default());
+        let simulation = run(&file, "0 down k\n20 down q\n100 up k\n110 up q\n");
+        assert_eq!(typed(&simulation), ["f", "a"]);
+        assert_eq!(
+            lines(&simulation),
+            ["0 down f", "0 up f", "0 down a", "100 up a"]
+        );
+        assert_eq!(
+            simulation.chords,
+            ChordStats {
+                detected: 1,
+                split: 0
+            }
+        );
+    }
+
+    #[test]
+    fn chord_outside_the_threshold_is_split() {
+        let file = rules_from_data("shingeta.rs", &RuleOptions::default());
+        let script = "0 down k\n80 down q\n150 up q\n160 up k\n";
+        let simulation = run(&file, script);
+        // k は い (i)、q は ー (JIS の hyphen)
+        assert_eq!(typed(&simulation), ["i", "hyphen"]);
+        assert_eq!(
+            simulation.chords,
+            ChordStats {
+                detected: 0,
+                split: 1
+            }
+        );
+
+        // 閾値を広げれば同じ入力が同時押しになる
+        let mut options = RuleOptions::default();
+        options.parameters.simultaneous_threshold_milliseconds = Some(100);
+        let file = rules_from_data("shingeta.rs", &options);
+        assert_eq!(typed(&run(&file, script)), ["f", "a"]);
+    }
+
+    #[test]
+    fn to_if_alone_on_tap_and_modifier_on_hold() {
+        let file = rules_from_data("modifiers_layout.rs", &RuleOptions::default());
+        let tap = run(&file, "0 down japanese_eisuu\n100 up japanese_eisuu\n");
+        assert_eq!(typed(&tap), ["left_control", "japanese_eisuu"]);
+
+        let hold = run(
+            &file,
+            "0 down japanese_eisuu\n100 down c\n150 up c\n200 up japanese_eisuu\n",
+        );
+        assert_eq!(typed(&hold), ["left_control", "left_control+c"]);
+
+        // to_if_alone_timeout を過ぎてから離すと単独押しにならない
+        let slow = run(&file, "0 down japanese_eisuu\n1500 up japanese_eisuu\n");
+        assert_eq!(typed(&slow), ["left_control"]);
+    }
+
+    #[test]
+    fn modifier_rules_from_modifiers_layout() {
+        let file = rules_from_data("modifiers_layout.rs", &RuleOptions::default());
+        let shift = run(
+            &file,
+            "0 down japanese_kana\n50 down a\n60 up a\n100 up japanese_kana\n",
+        );
+        assert_eq!(typed(&shift), ["left_shift", "left_shift+a"]);
+
+        let kana = run(&file, "0 down japanese_kana\n50 up japanese_kana\n");
+        assert_eq!(typed(&kana), ["left_shift", "japanese_kana"]);
+
+        let control = run(&file, "0 down left_control\n50 up left_control\n");
+        assert_eq!(typed(&control), ["japanese_kana"]);
+    }
+
+    #[test]
+    fn layer_is_driven_by_a_variable() {
+        let mappings = inline_mappings(&[
+            ("[layer nav]", "trigger=spacebar tap=spacebar"),
+            ("h", "left_arrow"),
+        ]);
+        let file = rules_from_mappings(&mappings, &RuleOptions::default());
+
+        let in_layer = run(
+            &file,
+            "0 down spacebar\n100 down h\n120 up h\n200 up spacebar\n300 down h\n320 up h\n",
+        );
+        // レイヤー中の h だけが left_arrow になり、他のキーを挟んだので spacebar は出ない
+        assert_eq!(typed(&in_layer), ["left_arrow", "h"]);
+
+        let tap = run(&file, "0 down spacebar\n50 up spacebar\n");
+        assert_eq!(typed(&tap), ["spacebar"]);
+    }
+
+    #[test]
+    fn interrupted_chord_depends_on_detect_key_down_uninterruptedly() {
+        let script = "0 down j\n10 down x\n20 down k\n100 up j\n110 up k\n120 up x\n";
+
+        // 既定では間に押された x を読み飛ばして同時押しとみなし、x は後から出力される
+        let file = rules_from_mappings(
+            &inline_mappings(&[("simul(j k)", "escape")]),
+            &RuleOptions::default(),
+        );
+        let simulation = run(&file, script);
+        assert_eq!(typed(&simulation), ["escape", "x"]);
+        assert_eq!(simulation.chords.detected, 1);
+
+        let file = rules_from_mappings(
+            &inline_mappings(&[("simul(j k; detect_key_down_uninterruptedly=true)", "escape")]),
+            &RuleOptions::default(),
+        );
+        let simulation = run(&file, script);
+        assert_eq!(typed(&simulation), ["j", "x", "k"]);
+        assert_eq!(simulation.chords.detected, 0);
+    }
+
+    #[test]
+    fn key_down_order_and_key_up_when() {
+        let file = rules_from_mappings(
+            &inline_mappings(&[(
+                "simul(j k; key_down_order=strict key_up_when=all)",
+                "escape",
+            )]),
+            &RuleOptions::default(),
+        );
+        let in_order = run(&file, "0 down j\n10 down k\n50 up j\n90 up k\n");
+        // key_up_when=all なのですべてのキーを離すまで押し続ける
+        assert_eq!(lines(&in_order), ["0 down escape", "90 up escape"]);
+
+        let reversed = run(&file, "0 down k\n10 down j\n50 up j\n90 up k\n");
+        assert_eq!(typed(&reversed), ["k", "j"]);
+    }
+
+    #[test]
+    fn to_after_key_up_follows_key_up_order() {
+        let file = rules_from_mappings(
+            &inline_mappings(&[(
+                "simul(j k; key_up_order=strict to_after_key_up=tab)",
+                "escape",
+            )]),
+            &RuleOptions::default(),
+        );
+        let in_order = run(&file, "0 down j\n10 down k\n50 up j\n90 up k\n");
+        assert_eq!(typed(&in_order), ["escape", "tab"]);
+        assert_eq!(lines(&in_order)[2..], ["90 down tab", "90 up tab"]);
+
+        let reversed = run(&file, "0 down j\n10 down k\n50 up k\n90 up j\n");
+        assert_eq!(typed(&reversed), ["escape"]);
+
+        let file = rules_from_mappings(
+            &inline_mappings(&[(
+                "simul(j k; key_up_order=strict_inverse to_after_key_up=tab)",
+                "escape",
+            )]),
+            &RuleOptions::default(),
+        );
+        let reversed = run(&file, "0 down j\n10 down k\n50 up k\n90 up j\n");
+        assert_eq!(typed(&reversed), ["escape", "tab"]);
+    }
+
+    #[test]
+    fn toggle_layer_stays_on_until_pressed_again() {
+        let mappings = inline_mappings(&[
+            ("[layer nav]", "trigger=caps_lock mode=toggle"),
+            ("h", "left_arrow"),
+        ]);
+        let file = rules_from_mappings(&mappings, &RuleOptions::default());
+        let simulation = run(
+            &file,
+            "0 down caps_lock\n10 up caps_lock\n100 down h\n110 up h\n\
+             200 down caps_lock\n210 up caps_lock\n300 down h\n310 up h\n",
+        );
+        assert_eq!(typed(&simulation), ["left_arrow", "h"]);
+    }
+
+    // manifest.json のルールには input_source_if が付くため、入力ソースを指定して調整する
+    #[test]
+    fn tune_threshold_against_manifest_output() {
+        let manifest = format!("{}/manifest.json", env!("CARGO_MANIFEST_DIR"));
+        let outputs = generate_from_manifest(&manifest, false).unwrap();
+        let shingeta = outputs
+            .iter()
+            .find(|output| output.path.ends_with("shingeta.json"))
+            .unwrap();
+        let mut file: File = serde_json::from_slice(&shingeta.contents).unwrap();
+        let events = parse_event_script(
+            "0 down k\n20 down q\n100 up k\n110 up q\n\
+             300 down k\n380 down q\n400 up k\n410 up q\n",
+        )
+        .unwrap();
+
+        let aquaskk = SimulatorEnvironment {
+            input_source_id: Some("jp.sourceforge.inputmethod.aquaskk.Hiragana".to_string()),
+            ..Default::default()
+        };
+        let results = tune_simultaneous_threshold(&mut file, &aquaskk, &events, [40, 100]).unwrap();
+        assert_eq!(
+            results,
+            [
+                (
+                    40,
+                    ChordStats {
+                        detected: 1,
+                        split: 1
+                    }
+                ),
+                (
+                    100,
+                    ChordStats {
+                        detected: 2,
+                        split: 0
+                    }
+                ),
+            ]
+        );
+
+        let other = tune_simultaneous_threshold(
+            &mut file,
+            &SimulatorEnvironment::default(),
+            &events,
+            [100],
+        )
+        .unwrap();
+        assert_eq!(other, [(100, ChordStats::default())]);
+    }
+
+    fn app_rule(bundle_identifier: &str) -> File {
+        let mut mappings = inline_mappings(&[("j", "escape")]);
+        mappings[0].applications.if_bundle_identifiers = vec![bundle_identifier.to_string()];
+        rules_from_mappings(&mappings, &RuleOptions::default())
+    }
+
+    fn typed_in(file: &File, bundle_identifier: &str) -> Vec<String> {
+        let environment = SimulatorEnvironment {
+            bundle_identifier: Some(bundle_identifier.to_string()),
+            ..Default::default()
+        };
+        let events = parse_event_script("0 down j\n10 up j\n").unwrap();
+        typed(&simulate(file, &environment, &events).unwrap())
+    }
+
+    #[test]
+    fn application_conditions_are_regular_expressions() {
+        let file = app_rule(r"^com\.apple\.(Terminal|Safari)$");
+        assert_eq!(typed_in(&file, "com.apple.Safari"), ["escape"]);
+        assert_eq!(typed_in(&file, "com.apple.Terminal"), ["escape"]);
+        assert_eq!(typed_in(&file, "com.apple.Finder"), ["j"]);
+        assert_eq!(typed_in(&file, "com.apple.Terminal2"), ["j"]);
+
+        let file = app_rule(".*");
+        assert_eq!(typed_in(&file, "org.example.Editor"), ["escape"]);
+
+        // 解釈できない正規表現 (先読み) は黙って不一致にせずエラーにする
+        let file = app_rule("^com\\.apple(?!\\.Finder)");
+        let events = parse_event_script("0 down j\n").unwrap();
+        let error = simulate(&file, &SimulatorEnvironment::default(), &events).unwrap_err();
+        assert!(error.contains("(?!"), "{}", error);
+    }
+
+    #[test]
+    fn held_down_fires_for_keys_still_pressed_at_the_end() {
+        let file = rules_from_mappings(
+            &inline_mappings(&[("j", "j hold(escape)")]),
+            &RuleOptions::default(),
+        );
+        let simulation = run(&file, "0 down j\n");
+        assert_eq!(typed(&simulation), ["j", "escape"]);
+        assert_eq!(
+            lines(&simulation)[1..],
+            ["500 down escape", "500 up escape"]
+        );
+
+        // 閾値より前に離せば出力しない
+        let simulation = run(&file, "0 down j\n200 up j\n");
+        assert_eq!(typed(&simulation), ["j"]);
+    }
+}
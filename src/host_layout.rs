@@ -0,0 +1,399 @@
+// 出力先の Mac で選択されているキーボードの種類 (ANSI/ISO/JIS)。
+// 記号を打つためのキーと修飾キーは種類ごとに異なるため、記号ごとの対応表をここにまとめる。
+
+use serde::Deserialize;
+
+use crate::json_structures::{ConditionVariant, Modifier};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostLayout {
+    #[default]
+    Jis,
+    Ansi,
+    Iso,
+}
+
+// 記号 1 文字を打つためのキーと、同時に押す修飾キー
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SymbolKey {
+    pub key_code: &'static str,
+    pub modifiers: &'static [Modifier],
+}
+
+const NONE: &[Modifier] = &[];
+const SHIFT: &[Modifier] = &[Modifier::LeftShift];
+const OPTION: &[Modifier] = &[Modifier::LeftOption];
+
+// (記号, キー名, 修飾キー)。英字と数字は表に含めず、キー名としてそのまま扱う。
+type SymbolTable = &'static [(&'static str, &'static str, &'static [Modifier])];
+
+// macOS の JIS キーボード。全角の記号は日本語入力中に対応するキーを押した時に入力されるもの。
+const JIS_SYMBOLS: SymbolTable = &[
+    (" ", "spacebar", NONE),
+    ("!", "1", SHIFT),
+    ("\"", "2", SHIFT),
+    ("#", "3", SHIFT),
+    ("$", "4", SHIFT),
+    ("%", "5", SHIFT),
+    ("&", "6", SHIFT),
+    ("'", "7", SHIFT),
+    ("(", "8", SHIFT),
+    (")", "9", SHIFT),
+    ("-", "hyphen", NONE),
+    ("=", "hyphen", SHIFT),
+    ("^", "equal_sign", NONE),
+    ("~", "equal_sign", SHIFT),
+    ("¥", "international3", NONE),
+    ("|", "international3", SHIFT),
+    ("\\", "international3", OPTION),
+    ("@", "open_bracket", NONE),
+    ("`", "open_bracket", SHIFT),
+    ("[", "close_bracket", NONE),
+    ("{", "close_bracket", SHIFT),
+    (";", "semicolon", NONE),
+    ("+", "semicolon", SHIFT),
+    (":", "quote", NONE),
+    ("*", "quote", SHIFT),
+    ("]", "backslash", NONE),
+    ("}", "backslash", SHIFT),
+    (",", "comma", NONE),
+    ("<", "comma", SHIFT),
+    (".", "period", NONE),
+    (">", "period", SHIFT),
+    ("/", "slash", NONE),
+    ("?", "slash", SHIFT),
+    ("_", "international1", NONE),
+    ("、", "comma", NONE),
+    ("。", "period", NONE),
+    ("・", "slash", NONE),
+    ("「", "close_bracket", NONE),
+    ("」", "backslash", NONE),
+    ("〜", "equal_sign", SHIFT),
+    ("！", "1", SHIFT),
+    ("？", "slash", SHIFT),
+];
+
+const ANSI_SYMBOLS: SymbolTable = &[
+    (" ", "spacebar", NONE),
+    ("`", "grave_accent_and_tilde", NONE),
+    ("~", "grave_accent_and_tilde", SHIFT),
+    ("!", "1", SHIFT),
+    ("@", "2", SHIFT),
+    ("#", "3", SHIFT),
+    ("$", "4", SHIFT),
+    ("%", "5", SHIFT),
+    ("^", "6", SHIFT),
+    ("&", "7", SHIFT),
+    ("*", "8", SHIFT),
+    ("(", "9", SHIFT),
+    (")", "0", SHIFT),
+    ("-", "hyphen", NONE),
+    ("_", "hyphen", SHIFT),
+    ("=", "equal_sign", NONE),
+    ("+", "equal_sign", SHIFT),
+    ("[", "open_bracket", NONE),
+    ("{", "open_bracket", SHIFT),
+    ("]", "close_bracket", NONE),
+    ("}", "close_bracket", SHIFT),
+    ("\\", "backslash", NONE),
+    ("|", "backslash", SHIFT),
+    (";", "semicolon", NONE),
+    (":", "semicolon", SHIFT),
+    ("'", "quote", NONE),
+    ("\"", "quote", SHIFT),
+    (",", "comma", NONE),
+    ("<", "comma", SHIFT),
+    (".", "period", NONE),
+    (">", "period", SHIFT),
+    ("/", "slash", NONE),
+    ("?", "slash", SHIFT),
+    ("、", "comma", NONE),
+    ("。", "period", NONE),
+    ("・", "slash", NONE),
+    ("「", "open_bracket", NONE),
+    ("」", "close_bracket", NONE),
+    ("〜", "grave_accent_and_tilde", SHIFT),
+    ("！", "1", SHIFT),
+    ("？", "slash", SHIFT),
+];
+
+// ISO キーボードでは macOS が 1 の左と Z の左のキーを入れ替えて扱うため、
+// US 入力ソースの ` と ~ は non_us_backslash から入力される
+const ISO_SYMBOLS: SymbolTable = &[
+    ("`", "non_us_backslash", NONE),
+    ("~", "non_us_backslash", SHIFT),
+    ("〜", "non_us_backslash", SHIFT),
+    ("§", "grave_accent_and_tilde", NONE),
+    ("±", "grave_accent_and_tilde", SHIFT),
+];
+
+impl HostLayout {
+    pub fn from_name(name: &str) -> Option<HostLayout> {
+        match name {
+            "jis" => Some(HostLayout::Jis),
+            "ansi" => Some(HostLayout::Ansi),
+            "iso" => Some(HostLayout::Iso),
+            _ => None,
+        }
+    }
+
+    // keyboard_type_if に書く名前
+    pub fn name(self) -> &'static str {
+        match self {
+            HostLayout::Jis => "jis",
+            HostLayout::Ansi => "ansi",
+            HostLayout::Iso => "iso",
+        }
+    }
+
+    fn tables(self) -> &'static [SymbolTable] {
+        match self {
+            HostLayout::Jis => &[JIS_SYMBOLS],
+            HostLayout::Ansi => &[ANSI_SYMBOLS],
+            // ISO は ANSI と異なるキーだけを先に引く
+            HostLayout::Iso => &[ISO_SYMBOLS, ANSI_SYMBOLS],
+        }
+    }
+
+    pub fn symbol_key(self, symbol: &str) -> Option<SymbolKey> {
+        self.tables().iter().find_map(|table| {
+            table
+                .iter()
+                .find(|(s, _, _)| *s == symbol)
+                .map(|&(_, key_code, modifiers)| SymbolKey {
+                    key_code,
+                    modifiers,
+                })
+        })
+    }
+
+    pub fn keyboard_type_if(self) -> ConditionVariant {
+        ConditionVariant::KeyboardTypeIf {
+            keyboard_types: vec![self.name().to_string()],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_structures::KeyCode;
+    use crate::karabiner_config_generator::{
+        generate_karabiner_rule, host_layout_variants, RuleOptions,
+    };
+    use crate::mapping_source::Mapping;
+
+    fn assert_symbols(layout: HostLayout, expected: &[(&str, &'static str, &'static [Modifier])]) {
+        for &(symbol, key_code, modifiers) in expected {
+            assert_eq!(
+                layout.symbol_key(symbol),
+                Some(SymbolKey {
+                    key_code,
+                    modifiers
+                }),
+                "{:?} {:?}",
+                layout,
+                symbol
+            );
+        }
+    }
+
+    // キートップの刻印から確かめた代表的な記号
+    #[test]
+    fn jis_symbols() {
+        assert_symbols(
+            HostLayout::Jis,
+            &[
+                ("@", "open_bracket", NONE),
+                (":", "quote", NONE),
+                ("*", "quote", SHIFT),
+                ("\"", "2", SHIFT),
+                ("'", "7", SHIFT),
+                ("^", "equal_sign", NONE),
+                ("_", "international1", NONE),
+                ("¥", "international3", NONE),
+                ("\\", "international3", OPTION),
+                ("]", "backslash", NONE),
+                ("「", "close_bracket", NONE),
+            ],
+        );
+    }
+
+    #[test]
+    fn ansi_symbols() {
+        assert_symbols(
+            HostLayout::Ansi,
+            &[
+                ("\"", "quote", SHIFT),
+                ("'", "quote", NONE),
+                ("@", "2", SHIFT),
+                (":", "semicolon", SHIFT),
+                ("^", "6", SHIFT),
+                ("_", "hyphen", SHIFT),
+                ("~", "grave_accent_and_tilde", SHIFT),
+                ("\\", "backslash", NONE),
+                (")", "0", SHIFT),
+                ("「", "open_bracket", NONE),
+            ],
+        );
+    }
+
+    #[test]
+    fn iso_symbols() {
+        assert_symbols(
+            HostLayout::Iso,
+            &[
+                ("`", "non_us_backslash", NONE),
+                ("~", "non_us_backslash", SHIFT),
+                ("§", "grave_accent_and_tilde", NONE),
+                // ISO 固有のキー以外は ANSI と同じ
+                ("@", "2", SHIFT),
+                ("\"", "quote", SHIFT),
+            ],
+        );
+    }
+
+    // 表に書いたキー名がすべて Karabiner のキー名であること
+    #[test]
+    fn tables_use_known_key_codes() {
+        for table in [JIS_SYMBOLS, ANSI_SYMBOLS, ISO_SYMBOLS] {
+            for (symbol, key_code, _) in table {
+                assert!(
+                    KeyCode::from_name(key_code).is_known(),
+                    "{:?} -> {:?}",
+                    symbol,
+                    key_code
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn uppercase_letters_are_typed_with_shift() {
+        for layout in [HostLayout::Jis, HostLayout::Ansi, HostLayout::Iso] {
+            let mut options = RuleOptions::default();
+            options.typing.layout = layout;
+            let typed: Vec<(String, Option<Vec<Modifier>>)> = ["A", "text:Ab"]
+                .iter()
+                .map(|to| generate_karabiner_rule("t".to_string(), &[mapping("q", to)], &options))
+                .flat_map(|rule| rule.manipulators[0].to.clone())
+                .map(|to| (to.key_code.unwrap().name().to_string(), to.modifiers))
+                .collect();
+            let shift = Some(vec![Modifier::LeftShift]);
+            assert_eq!(
+                typed,
+                [
+                    ("a".to_string(), shift.clone()),
+                    ("a".to_string(), shift),
+                    ("b".to_string(), None)
+                ],
+                "{:?}",
+                layout
+            );
+        }
+    }
+
+    #[test]
+    fn letters_and_digits_are_not_in_the_tables() {
+        for layout in [HostLayout::Jis, HostLayout::Ansi, HostLayout::Iso] {
+            assert_eq!(layout.symbol_key("a"), None);
+            assert_eq!(layout.symbol_key("1"), None);
+        }
+    }
+
+    fn mapping(from: &str, to: &str) -> Mapping {
+        Mapping {
+            from: from.to_string(),
+            to: to.to_string(),
+            applications: Default::default(),
+            location: None,
+        }
+    }
+
+    #[test]
+    fn symbols_are_typed_with_the_host_layout_keys() {
+        let mappings = [mapping("q", "@")];
+        for (layout, key_code, modifiers) in [
+            (HostLayout::Jis, "open_bracket", None),
+            (HostLayout::Ansi, "2", Some(vec![Modifier::LeftShift])),
+            (HostLayout::Iso, "2", Some(vec![Modifier::LeftShift])),
+        ] {
+            let mut options = RuleOptions::default();
+            options.typing.layout = layout;
+            let rule = generate_karabiner_rule("t".to_string(), &mappings, &options);
+            let to = &rule.manipulators[0].to[0];
+            assert_eq!(to.key_code, Some(KeyCode::from_name(key_code)));
+            assert_eq!(to.modifiers, modifiers);
+        }
+    }
+
+    #[test]
+    fn from_keys_do_not_depend_on_the_host_layout() {
+        let mappings = [
+            mapping("@", "z"),
+            mapping(":", "v"),
+            mapping("+", "x"),
+            mapping("simul(d @)", "y"),
+        ];
+        let froms = |layout: HostLayout| -> Vec<serde_json::Value> {
+            let mut options = RuleOptions::default();
+            options.typing.layout = layout;
+            let rule = generate_karabiner_rule("t".to_string(), &mappings, &options);
+            rule.manipulators
+                .iter()
+                .map(|manipulator| serde_json::to_value(&manipulator.from).unwrap())
+                .collect()
+        };
+        let jis = froms(HostLayout::Jis);
+        assert_eq!(froms(HostLayout::Ansi), jis);
+        assert_eq!(froms(HostLayout::Iso), jis);
+        // from は JIS キーボードの物理キー
+        assert_eq!(jis[0]["key_code"], "open_bracket");
+        assert_eq!(jis[1]["key_code"], "quote");
+        assert_eq!(jis[2]["key_code"], "semicolon");
+        assert_eq!(jis[2]["modifiers"]["mandatory"][0], "left_shift");
+        assert_eq!(jis[3]["simultaneous"][1]["key_code"], "open_bracket");
+    }
+
+    fn keyboard_types(conditions: &[ConditionVariant]) -> Vec<String> {
+        conditions
+            .iter()
+            .filter_map(|condition| match condition {
+                ConditionVariant::KeyboardTypeIf { keyboard_types } => {
+                    Some(keyboard_types.join(","))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keyboard_type_if_is_emitted() {
+        let mappings = [mapping("q", "@")];
+        let variants = host_layout_variants(&[HostLayout::Jis, HostLayout::Ansi], false);
+        let mut emitted = Vec::new();
+        for variant in &variants {
+            let rule = generate_karabiner_rule(
+                variant.describe("t"),
+                &mappings,
+                &variant.apply(&RuleOptions::default()),
+            );
+            let conditions = rule.manipulators[0].conditions.clone().unwrap_or_default();
+            emitted.extend(keyboard_types(&conditions));
+            let json = serde_json::to_value(&rule).unwrap();
+            assert_eq!(
+                json["manipulators"][0]["conditions"][0]["type"],
+                "keyboard_type_if"
+            );
+        }
+        assert_eq!(emitted, ["jis", "ansi"]);
+
+        // 配列が 1 つでも keyboard_type_if を指定すれば付ける
+        let single = host_layout_variants(&[HostLayout::Iso], true);
+        assert_eq!(keyboard_types(&single[0].conditions), ["iso"]);
+        // 指定しなければ付けない
+        let single = host_layout_variants(&[HostLayout::Iso], false);
+        assert!(keyboard_types(&single[0].conditions).is_empty());
+    }
+}
@@ -0,0 +1,358 @@
+// 仮名の出力を、IME の独自ローマ字テーブルに登録する短縮コードで打つ。
+// Karabiner が kyo や fa のような複数キーを続けて送ると IME 側で取りこぼしや誤変換が起きやすいため、
+// 複数キーになる仮名ごとに「接頭辞 + 英小文字」のコード (qa, qb, ..., qy, qza, ...) を割り当て、
+// そのコードを仮名に戻す IME 用のテーブルを Karabiner の JSON と一緒に書き出す。
+// z は次の桁に続く印としてだけ使うので、あるコードが他のコードの先頭部分になることはない。
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+
+use serde::Deserialize;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::keycode_mapping::{normalize_kana, parse_to_input_string};
+use crate::mapping_sections::is_section_header;
+use crate::mapping_source::Mapping;
+use crate::romaji_scheme::{katakana_to_hiragana, RomajiScheme};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImeTableFormat {
+    // Google 日本語入力のローマ字テーブル (入力<TAB>出力)
+    #[default]
+    Google,
+    // AquaSKK の kana-rule.conf (EUC-JP)
+    Aquaskk,
+    // macSKK の kana-rule.conf (UTF-8)
+    Macskk,
+}
+
+impl ImeTableFormat {
+    pub fn from_name(name: &str) -> Option<ImeTableFormat> {
+        match name {
+            "google" => Some(ImeTableFormat::Google),
+            "aquaskk" => Some(ImeTableFormat::Aquaskk),
+            "macskk" => Some(ImeTableFormat::Macskk),
+            _ => None,
+        }
+    }
+
+    // 既定の接頭辞。ヘボン式の表で q と l から始まる綴りはないが、SKK ではどちらも入力の切り替え
+    // (q は片仮名、l は英数) に使われるため、SKK には記号入力 (z, z. zh など) と重ならない zq を使う。
+    pub fn default_prefix(self) -> &'static str {
+        match self {
+            ImeTableFormat::Google => "q",
+            ImeTableFormat::Aquaskk | ImeTableFormat::Macskk => "zq",
+        }
+    }
+
+    // IME がキー 1 つで入力の切り替えに使うため、接頭辞の先頭に置けない文字
+    fn reserved_letters(self) -> &'static [char] {
+        match self {
+            ImeTableFormat::Google => &[],
+            ImeTableFormat::Aquaskk | ImeTableFormat::Macskk => &['q', 'l'],
+        }
+    }
+}
+
+// マニフェストの ime_table。prefix を省略すると format の既定の接頭辞を使う。
+#[derive(Deserialize, Debug, Clone)]
+pub struct ImeTableSpec {
+    pub output: String,
+    #[serde(default)]
+    pub format: ImeTableFormat,
+    #[serde(default)]
+    pub prefix: Option<String>,
+}
+
+impl ImeTableSpec {
+    pub fn prefix(&self) -> &str {
+        self.prefix
+            .as_deref()
+            .unwrap_or_else(|| self.format.default_prefix())
+    }
+}
+
+#[derive(Debug)]
+pub enum ImeTableError {
+    InvalidPrefix(String),
+    FileWriteError(String),
+}
+
+impl std::fmt::Display for ImeTableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImeTableError::InvalidPrefix(s) => write!(f, "Invalid short code prefix: {}", s),
+            ImeTableError::FileWriteError(s) => write!(f, "File write error: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for ImeTableError {}
+
+// (仮名, コード) をマッピングに現れた順に持つ
+#[derive(Debug, Default)]
+pub struct ShortCodes {
+    entries: Vec<(String, String)>,
+}
+
+// マッピングの出力 (tap/hold の中身を含む) のうち仮名だけからなるものにコードを割り当てる。
+// 綴りの長い仮名 (綴りのないものが最優先) から順に短いコードを使い、コードより短い綴りで打てる仮名はそのままにする。
+pub fn assign_short_codes<'a>(
+    mappings: impl IntoIterator<Item = &'a Mapping>,
+    romaji: &RomajiScheme,
+    prefix: &str,
+    format: ImeTableFormat,
+) -> Result<ShortCodes, ImeTableError> {
+    if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_lowercase()) {
+        return Err(ImeTableError::InvalidPrefix(format!(
+            "'{}' must be one or more lowercase letters",
+            prefix
+        )));
+    }
+    if let Some(reserved) = prefix
+        .chars()
+        .next()
+        .filter(|c| format.reserved_letters().contains(c))
+    {
+        return Err(ImeTableError::InvalidPrefix(format!(
+            "'{}' starts with '{}', which switches the input mode in SKK (try '{}')",
+            prefix,
+            reserved,
+            format.default_prefix()
+        )));
+    }
+    if let Some(spelling) = romaji.spellings().find(|s| s.starts_with(prefix)) {
+        return Err(ImeTableError::InvalidPrefix(format!(
+            "'{}' is already used by the romaji spelling '{}'",
+            prefix, spelling
+        )));
+    }
+
+    let mut seen = HashSet::new();
+    let mut targets = Vec::new();
+    for mapping in mappings {
+        if is_section_header(mapping) {
+            continue;
+        }
+        let parsed = parse_to_input_string(&mapping.to);
+        for to in [parsed.to, parsed.to_if_alone, parsed.to_if_held_down]
+            .into_iter()
+            .flatten()
+        {
+            let kana = normalize_kana(&to);
+            if is_kana_text(&kana) && seen.insert(kana.clone()) {
+                let spelling_length = romaji.romaji(&kana).map(|s| s.chars().count());
+                targets.push((kana, spelling_length));
+            }
+        }
+    }
+
+    targets.sort_by_key(|(_, spelling_length)| Reverse(spelling_length.unwrap_or(usize::MAX)));
+    let mut entries = Vec::new();
+    for (kana, spelling_length) in targets {
+        let code = short_code(prefix, entries.len());
+        if spelling_length.is_some_and(|n| n <= code.len()) {
+            break;
+        }
+        entries.push((kana, code));
+    }
+    Ok(ShortCodes { entries })
+}
+
+fn is_kana_text(text: &str) -> bool {
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|c| matches!(c, '\u{3041}'..='\u{3096}' | '\u{30A1}'..='\u{30FA}' | 'ー'))
+}
+
+// index 番目のコード。a〜y の 25 個ごとに z を 1 つ足す。
+fn short_code(prefix: &str, index: usize) -> String {
+    let letter = char::from(b'a' + (index % 25) as u8);
+    format!("{}{}{}", prefix, "z".repeat(index / 25), letter)
+}
+
+impl ShortCodes {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // ローマ字の綴りの代わりにコードを打つようにする
+    pub fn apply(&self, romaji: &mut RomajiScheme) {
+        for (kana, code) in &self.entries {
+            romaji.insert(kana, code);
+        }
+    }
+
+    pub fn render(&self, format: ImeTableFormat) -> Vec<u8> {
+        match format {
+            ImeTableFormat::Google => {
+                let mut rendered = String::new();
+                for (kana, code) in &self.entries {
+                    rendered.push_str(&format!("{}\t{}\n", code, kana));
+                }
+                rendered.into_bytes()
+            }
+            ImeTableFormat::Aquaskk => {
+                let rendered = self.render_kana_rule();
+                let (bytes, _, _) = encoding_rs::EUC_JP.encode(&rendered);
+                bytes.into_owned()
+            }
+            ImeTableFormat::Macskk => self.render_kana_rule().into_bytes(),
+        }
+    }
+
+    // ローマ字,ひらがな,カタカナ,半角カナ
+    fn render_kana_rule(&self) -> String {
+        let mut rendered = String::from("# make_karabiner が生成した短縮コード\n");
+        for (kana, code) in &self.entries {
+            let hiragana = katakana_to_hiragana(kana);
+            let katakana = hiragana_to_katakana(&hiragana);
+            let halfwidth = to_halfwidth_katakana(&katakana);
+            rendered.push_str(&format!(
+                "{},{},{},{}\n",
+                code, hiragana, katakana, halfwidth
+            ));
+        }
+        rendered
+    }
+
+    pub fn write(&self, file_path: &str, format: ImeTableFormat) -> Result<(), ImeTableError> {
+        fs::write(file_path, self.render(format)).map_err(|e| {
+            ImeTableError::FileWriteError(format!("Failed to write to {}: {}", file_path, e))
+        })
+    }
+}
+
+fn hiragana_to_katakana(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\u{3041}'..='\u{3096}' => char::from_u32(c as u32 + 0x60).unwrap_or(c),
+            other => other,
+        })
+        .collect()
+}
+
+// 半角カナ (U+FF66〜U+FF9D) は NFKC で全角になるので、その逆引きで変換する。
+// 濁音・半濁音は清音と半角の濁点・半濁点に分け、半角にない文字 (ヮ など) は全角のまま残す。
+fn to_halfwidth_katakana(text: &str) -> String {
+    text.nfd()
+        .map(|c| match c {
+            '\u{3099}' => '\u{FF9E}',
+            '\u{309A}' => '\u{FF9F}',
+            other => ('\u{FF66}'..='\u{FF9D}')
+                .find(|halfwidth| halfwidth.to_string().nfkc().eq([other]))
+                .unwrap_or(other),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [ImeTableFormat; 3] = [
+        ImeTableFormat::Google,
+        ImeTableFormat::Aquaskk,
+        ImeTableFormat::Macskk,
+    ];
+
+    fn mappings() -> Vec<Mapping> {
+        [("j", "きょう"), ("k", "っ"), ("l", "か")]
+            .into_iter()
+            .map(|(from, to)| Mapping {
+                from: from.to_string(),
+                to: to.to_string(),
+                applications: Default::default(),
+                location: None,
+            })
+            .collect()
+    }
+
+    fn assign(prefix: &str, format: ImeTableFormat) -> Result<ShortCodes, ImeTableError> {
+        assign_short_codes(&mappings(), &RomajiScheme::default(), prefix, format)
+    }
+
+    #[test]
+    fn default_prefix_is_accepted_by_each_format() {
+        for format in FORMATS {
+            let prefix = format.default_prefix();
+            let short_codes = assign(prefix, format).unwrap();
+            // 綴りのない きょう と 4 文字の綴り (xtsu) の っ にだけコードを付ける
+            let kana: Vec<&str> = short_codes
+                .entries
+                .iter()
+                .map(|(k, _)| k.as_str())
+                .collect();
+            assert_eq!(kana, ["きょう", "っ"], "{:?}", format);
+            for (_, code) in &short_codes.entries {
+                assert!(code.starts_with(prefix));
+            }
+        }
+    }
+
+    #[test]
+    fn skk_formats_do_not_default_to_q() {
+        assert_eq!(ImeTableFormat::Google.default_prefix(), "q");
+        assert_eq!(ImeTableFormat::Aquaskk.default_prefix(), "zq");
+        assert_eq!(ImeTableFormat::Macskk.default_prefix(), "zq");
+    }
+
+    #[test]
+    fn skk_formats_reject_mode_switching_prefixes() {
+        assert!(assign("q", ImeTableFormat::Google).is_ok());
+        for format in [ImeTableFormat::Aquaskk, ImeTableFormat::Macskk] {
+            for prefix in ["q", "l", "qz"] {
+                let error = assign(prefix, format).unwrap_err();
+                assert!(
+                    matches!(error, ImeTableError::InvalidPrefix(_)),
+                    "{:?} {:?}",
+                    format,
+                    prefix
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn prefix_that_starts_a_spelling_is_rejected() {
+        for format in FORMATS {
+            assert!(assign("k", format).is_err());
+            assert!(assign("Q", format).is_err());
+        }
+    }
+
+    #[test]
+    fn manifest_spec_uses_the_format_default() {
+        let spec: ImeTableSpec =
+            serde_json::from_str(r#"{"output": "x", "format": "macskk"}"#).unwrap();
+        assert_eq!(spec.prefix(), "zq");
+        let spec: ImeTableSpec = serde_json::from_str(r#"{"output": "x"}"#).unwrap();
+        assert_eq!(spec.prefix(), "q");
+        let spec: ImeTableSpec =
+            serde_json::from_str(r#"{"output": "x", "format": "aquaskk", "prefix": "zx"}"#)
+                .unwrap();
+        assert_eq!(spec.prefix(), "zx");
+    }
+
+    #[test]
+    fn render_each_format() {
+        let google = assign("q", ImeTableFormat::Google).unwrap();
+        assert_eq!(
+            String::from_utf8(google.render(ImeTableFormat::Google)).unwrap(),
+            "qa\tきょう\nqb\tっ\n"
+        );
+
+        let skk = assign("zq", ImeTableFormat::Macskk).unwrap();
+        let macskk = String::from_utf8(skk.render(ImeTableFormat::Macskk)).unwrap();
+        assert!(macskk.ends_with("zqa,きょう,キョウ,ｷｮｳ\nzqb,っ,ッ,ｯ\n"));
+
+        let aquaskk = skk.render(ImeTableFormat::Aquaskk);
+        let (decoded, _, had_errors) = encoding_rs::EUC_JP.decode(&aquaskk);
+        assert!(!had_errors);
+        assert_eq!(decoded, macskk);
+        assert!(std::str::from_utf8(&aquaskk).is_err());
+    }
+}
@@ -1,53 +1,196 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Debug)]
+pub use crate::karabiner_key_codes::{KeyCode, Modifier};
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct File {
+    // complex_modifications のアセットとして読み込ませる場合に必要
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
     pub rules: Vec<Rule>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Rule {
     pub description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    // --merge-into で karabiner.json に書き込むルールの目印 (入力ファイル名)。
+    // description が変わっても、前回書き込んだルールを見つけて置き換えられるようにする。
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub make_karabiner_id: Option<String>,
     pub manipulators: Vec<Manipulator>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Manipulator {
     pub from: From,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub to: Vec<ToEvent>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub to_if_alone: Vec<ToEvent>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub to_if_held_down: Vec<ToEvent>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub to_after_key_up: Vec<ToEvent>,
     #[serde(default)]
     pub r#type: String,
     #[serde(skip_serializing_if = "Option::is_none", default)]
     pub conditions: Option<Vec<ConditionVariant>>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub parameters: Option<Parameters>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Parameters {
+    #[serde(
+        rename = "basic.to_if_alone_timeout_milliseconds",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub to_if_alone_timeout_milliseconds: Option<u64>,
+    #[serde(
+        rename = "basic.to_if_held_down_threshold_milliseconds",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub to_if_held_down_threshold_milliseconds: Option<u64>,
+    #[serde(
+        rename = "basic.to_delayed_action_delay_milliseconds",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub to_delayed_action_delay_milliseconds: Option<u64>,
+    #[serde(
+        rename = "basic.simultaneous_threshold_milliseconds",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub simultaneous_threshold_milliseconds: Option<u64>,
+}
+
+impl Parameters {
+    // other で指定された項目を優先して重ねる
+    pub fn overridden_by(&self, other: &Parameters) -> Parameters {
+        Parameters {
+            to_if_alone_timeout_milliseconds: other
+                .to_if_alone_timeout_milliseconds
+                .or(self.to_if_alone_timeout_milliseconds),
+            to_if_held_down_threshold_milliseconds: other
+                .to_if_held_down_threshold_milliseconds
+                .or(self.to_if_held_down_threshold_milliseconds),
+            to_delayed_action_delay_milliseconds: other
+                .to_delayed_action_delay_milliseconds
+                .or(self.to_delayed_action_delay_milliseconds),
+            simultaneous_threshold_milliseconds: other
+                .simultaneous_threshold_milliseconds
+                .or(self.simultaneous_threshold_milliseconds),
+        }
+    }
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SimultaneousKey {
-    pub key_code: String,
+    pub key_code: KeyCode,
 }
 
-#[derive(Serialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct From {
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub key_code: Option<String>,
+    pub key_code: Option<KeyCode>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub modifiers: Option<Modifiers>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub simultaneous: Option<Vec<SimultaneousKey>>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub simultaneous_options: Option<SimultaneousOptions>,
+}
+
+// 同時押しのキーを押す順序・離す順序
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyOrder {
+    Insensitive,
+    // simultaneous に並べた順
+    Strict,
+    // simultaneous に並べた逆順
+    StrictInverse,
+}
+
+impl KeyOrder {
+    pub fn from_name(name: &str) -> Option<KeyOrder> {
+        match name {
+            "insensitive" => Some(KeyOrder::Insensitive),
+            "strict" => Some(KeyOrder::Strict),
+            "strict_inverse" => Some(KeyOrder::StrictInverse),
+            _ => None,
+        }
+    }
+}
+
+// 同時押しの出力を、どれか 1 つ (any) かすべて (all) のキーを離した時点で終える
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyUpWhen {
+    Any,
+    All,
+}
+
+impl KeyUpWhen {
+    pub fn from_name(name: &str) -> Option<KeyUpWhen> {
+        match name {
+            "any" => Some(KeyUpWhen::Any),
+            "all" => Some(KeyUpWhen::All),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SimultaneousOptions {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub detect_key_down_uninterruptedly: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub key_down_order: Option<KeyOrder>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub key_up_order: Option<KeyOrder>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub key_up_when: Option<KeyUpWhen>,
+    // 同時押しのキーをすべて離した後に出力する
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub to_after_key_up: Vec<ToEvent>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ToEvent {
-    pub key_code: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub modifiers: Option<Vec<String>>,
+    pub key_code: Option<KeyCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modifiers: Option<Vec<Modifier>>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub set_variable: Option<SetVariable>,
+}
+
+// レイヤーなどの状態を Karabiner の変数に保持する
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetVariable {
+    pub name: String,
+    pub value: VariableValue,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum VariableValue {
+    Int(i64),
+    Bool(bool),
+    Str(String),
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Modifiers {
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
-    pub mandatory: Vec<String>,
+    pub mandatory: Vec<Modifier>,
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
-    pub optional: Vec<String>,
+    pub optional: Vec<Modifier>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -56,6 +199,21 @@ pub struct InputSourceDetail {
     pub input_source_id: Option<String>,
 }
 
+// device_if / device_unless で対象とするデバイス。指定した項目がすべて一致するものに適用される。
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DeviceIdentifier {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub vendor_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub product_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub location_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub is_keyboard: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub is_built_in_keyboard: Option<bool>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum ConditionVariant {
@@ -63,4 +221,28 @@ pub enum ConditionVariant {
     InputSourceIf {
         input_sources: Vec<InputSourceDetail>,
     },
+    #[serde(rename = "frontmost_application_if")]
+    FrontmostApplicationIf {
+        #[serde(skip_serializing_if = "Vec::is_empty", default)]
+        bundle_identifiers: Vec<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty", default)]
+        file_paths: Vec<String>,
+    },
+    #[serde(rename = "frontmost_application_unless")]
+    FrontmostApplicationUnless {
+        #[serde(skip_serializing_if = "Vec::is_empty", default)]
+        bundle_identifiers: Vec<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty", default)]
+        file_paths: Vec<String>,
+    },
+    #[serde(rename = "device_if")]
+    DeviceIf { identifiers: Vec<DeviceIdentifier> },
+    #[serde(rename = "device_unless")]
+    DeviceUnless { identifiers: Vec<DeviceIdentifier> },
+    #[serde(rename = "keyboard_type_if")]
+    KeyboardTypeIf { keyboard_types: Vec<String> },
+    #[serde(rename = "variable_if")]
+    VariableIf { name: String, value: VariableValue },
+    #[serde(rename = "variable_unless")]
+    VariableUnless { name: String, value: VariableValue },
 }
@@ -0,0 +1,128 @@
+// 仮名を IME にどう入力するか。ローマ字入力では romaji_scheme の綴りを打ち、
+// JIS かな入力では仮名ごとに刻印されたキーを 1 つ (濁音・半濁音は濁点のキーを続けて 2 つ) 打つ。
+
+use serde::Deserialize;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::host_layout::SymbolKey;
+use crate::json_structures::Modifier;
+use crate::romaji_scheme::katakana_to_hiragana;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KanaInput {
+    #[default]
+    Romaji,
+    JisKana,
+}
+
+impl KanaInput {
+    pub fn from_name(name: &str) -> Option<KanaInput> {
+        match name {
+            "romaji" => Some(KanaInput::Romaji),
+            "jis_kana" => Some(KanaInput::JisKana),
+            _ => None,
+        }
+    }
+}
+
+const NONE: &[Modifier] = &[];
+const SHIFT: &[Modifier] = &[Modifier::LeftShift];
+
+// macOS の JIS キーボードでかな入力にした時の刻印。小さい仮名は Shift を押しながら打つ。
+// ゐ・ゑ・ゎ・ゕ・ゖ には対応するキーがない。
+const JIS_KANA_KEYS: &[(char, &str, &[Modifier])] = &[
+    ('ぬ', "1", NONE),
+    ('ふ', "2", NONE),
+    ('あ', "3", NONE),
+    ('ぁ', "3", SHIFT),
+    ('う', "4", NONE),
+    ('ぅ', "4", SHIFT),
+    ('え', "5", NONE),
+    ('ぇ', "5", SHIFT),
+    ('お', "6", NONE),
+    ('ぉ', "6", SHIFT),
+    ('や', "7", NONE),
+    ('ゃ', "7", SHIFT),
+    ('ゆ', "8", NONE),
+    ('ゅ', "8", SHIFT),
+    ('よ', "9", NONE),
+    ('ょ', "9", SHIFT),
+    ('わ', "0", NONE),
+    ('を', "0", SHIFT),
+    ('ほ', "hyphen", NONE),
+    ('へ', "equal_sign", NONE),
+    ('ー', "international3", NONE),
+    ('た', "q", NONE),
+    ('て', "w", NONE),
+    ('い', "e", NONE),
+    ('ぃ', "e", SHIFT),
+    ('す', "r", NONE),
+    ('か', "t", NONE),
+    ('ん', "y", NONE),
+    ('な', "u", NONE),
+    ('に', "i", NONE),
+    ('ら', "o", NONE),
+    ('せ', "p", NONE),
+    ('\u{3099}', "open_bracket", NONE),
+    ('\u{309A}', "close_bracket", NONE),
+    ('「', "close_bracket", SHIFT),
+    ('ち', "a", NONE),
+    ('と', "s", NONE),
+    ('し', "d", NONE),
+    ('は', "f", NONE),
+    ('き', "g", NONE),
+    ('く', "h", NONE),
+    ('ま', "j", NONE),
+    ('の', "k", NONE),
+    ('り', "l", NONE),
+    ('れ', "semicolon", NONE),
+    ('け', "quote", NONE),
+    ('む', "backslash", NONE),
+    ('」', "backslash", SHIFT),
+    ('つ', "z", NONE),
+    ('っ', "z", SHIFT),
+    ('さ', "x", NONE),
+    ('そ', "c", NONE),
+    ('ひ', "v", NONE),
+    ('こ', "b", NONE),
+    ('み', "n", NONE),
+    ('も', "m", NONE),
+    ('ね', "comma", NONE),
+    ('、', "comma", SHIFT),
+    ('る', "period", NONE),
+    ('。', "period", SHIFT),
+    ('め', "slash", NONE),
+    ('・', "slash", SHIFT),
+    ('ろ', "international1", NONE),
+];
+
+pub fn jis_kana_key(kana: char) -> Option<SymbolKey> {
+    JIS_KANA_KEYS
+        .iter()
+        .find(|(c, _, _)| *c == kana)
+        .map(|&(_, key_code, modifiers)| SymbolKey {
+            key_code,
+            modifiers,
+        })
+}
+
+// 仮名 (と 、。・「」ー) だけからなる文字列なら、1 文字ずつのキーに分ける。
+// 濁音・半濁音は清音と濁点に分解 (NFD) して 2 つのキーにする。
+// 対応するキーがない仮名は None のまま返し、呼び出し側で不明なキーとして扱う。
+pub fn jis_kana_keys(text: &str) -> Option<Vec<(char, Option<SymbolKey>)>> {
+    let decomposed: Vec<char> = katakana_to_hiragana(text).nfd().collect();
+    if decomposed.is_empty() || !decomposed.iter().all(|&c| is_kana(c)) {
+        return None;
+    }
+    Some(
+        decomposed
+            .into_iter()
+            .map(|c| (c, jis_kana_key(c)))
+            .collect(),
+    )
+}
+
+fn is_kana(c: char) -> bool {
+    matches!(c, '\u{3041}'..='\u{309A}') || jis_kana_key(c).is_some()
+}
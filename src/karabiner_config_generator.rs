@@ -1,217 +1,799 @@
+use serde::Deserialize;
+
+use crate::host_layout::HostLayout;
 use crate::json_structures::{
-    ConditionVariant, File, From, Manipulator, Modifiers, Rule, SimultaneousKey, ToEvent,
+    ConditionVariant, File, InputSourceDetail, KeyOrder, KeyUpWhen, Manipulator, Modifier,
+    Modifiers, Parameters, Rule, SetVariable, SimultaneousKey, SimultaneousOptions, ToEvent,
+    VariableValue,
 };
 use crate::keycode_mapping::{
-    parse_from_input_string, transform_string_for_to_event, FromEventType, ParsedFromEvent,
-    TransformedToKey,
+    parse_from_input_string, parse_to_input_string, transform_string_for_to_event, FromEventType,
+    ParsedFromEvent, ParsedToEvent, TypingEnvironment,
+};
+use crate::mapping_sections::{
+    parse_option_tokens, split_into_sections, LayerMode, LayerSpec, SectionKind,
 };
+use crate::mapping_source::Mapping;
 
-fn is_known_single_multichar_keycode(s: &str) -> bool {
-    // このリストは、Karabiner Elementsが単一のキーイベントとして認識する
-    // 複数文字のキーコード名を含みます。
-    // process_key_symbol が返す可能性のあるものを中心に。
-    match s {
-        "left_control"
-        | "right_control"
-        | "left_shift"
-        | "right_shift"
-        | "left_option"
-        | "right_option"
-        | "left_command"
-        | "right_command"
-        | "delete_or_backspace"
-        | "delete_forward"
-        | "escape"
-        | "return_or_enter"
-        | "spacebar"
-        | "tab"
-        | "page_up"
-        | "page_down"
-        | "home"
-        | "end"
-        | "up_arrow"
-        | "down_arrow"
-        | "left_arrow"
-        | "right_arrow"
-        | "f1"
-        | "f2"
-        | "f3"
-        | "f4"
-        | "f5"
-        | "f6"
-        | "f7"
-        | "f8"
-        | "f9"
-        | "f10"
-        | "f11"
-        | "f12"
-        | "f13"
-        | "f14"
-        | "f15"
-        | "f16"
-        | "f17"
-        | "f18"
-        | "f19"
-        | "f20"
-        | "semicolon"
-        | "hyphen"
-        | "equal_sign"
-        | "open_bracket"
-        | "close_bracket"
-        | "backslash"
-        | "quote"
-        | "comma"
-        | "period"
-        | "slash"
-        | "international1"
-        | "japanese_eisuu"
-        | "japanese_kana"
-        | "caps_lock" => true,
-        _ => s.starts_with("keypad_") || s.starts_with("vk_"), // 他の一般的なプレフィックス
-    }
-}
-
-fn add_left_shift(current_modifiers: &[String]) -> Vec<String> {
+fn add_left_shift(current_modifiers: &[Modifier]) -> Vec<Modifier> {
     let mut new_modifiers = current_modifiers.to_vec();
-    let shift_key = "left_shift".to_string();
-    if !new_modifiers.contains(&shift_key) {
-        new_modifiers.push(shift_key);
+    if !new_modifiers.contains(&Modifier::LeftShift) {
+        new_modifiers.push(Modifier::LeftShift);
     }
     new_modifiers
 }
 
-pub fn generate_karabiner_config(
-    description: String,
-    mappings_to_process: &[(String, String)],
-    set_from_optional_any: bool,
-    global_manipulator_conditions: Option<Vec<ConditionVariant>>,
-) -> File {
-    let mut final_manipulators: Vec<Manipulator> = Vec::new();
+fn build_to_events(to_input_str: &str, shifted: bool, env: &TypingEnvironment) -> Vec<ToEvent> {
+    let mut to_events = transform_string_for_to_event(to_input_str, env);
+    if shifted {
+        for event in to_events.iter_mut() {
+            event.modifiers = Some(add_left_shift(
+                event.modifiers.as_deref().unwrap_or_default(),
+            ));
+        }
+    }
+    to_events
+}
 
-    for (from_input_str_ref, to_input_str_ref) in mappings_to_process.iter() {
-        let from_input_str: &str = from_input_str_ref;
-        let parsed_from_event: ParsedFromEvent = parse_from_input_string(from_input_str);
+fn build_optional_to_events(
+    to_input_str: &Option<String>,
+    shifted: bool,
+    env: &TypingEnvironment,
+) -> Vec<ToEvent> {
+    match to_input_str {
+        Some(s) => build_to_events(s, shifted, env),
+        None => Vec::new(),
+    }
+}
 
-        let mut from_object_for_manipulator = crate::json_structures::From::default();
+// from 側に Shift を加えた変種 (大文字入力用) をどのマッピングに生成するか
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShiftVariantPolicy {
+    // 単一の英小文字キーと同時押しにだけ生成する
+    #[default]
+    Auto,
+    Never,
+}
 
-        match parsed_from_event.event_type {
-            FromEventType::SingleKey => {
-                from_object_for_manipulator.key_code = parsed_from_event.key_code.clone();
-                let mandatory_mods = parsed_from_event.modifiers.clone();
-                let optional_mods = if set_from_optional_any {
-                    vec!["any".to_string()]
-                } else {
-                    Vec::new()
-                };
-
-                if !mandatory_mods.is_empty() || !optional_mods.is_empty() {
-                    from_object_for_manipulator.modifiers = Some(Modifiers {
-                        mandatory: mandatory_mods,
-                        optional: optional_mods,
-                    });
-                }
+#[derive(Debug, Default, Clone)]
+pub struct RuleOptions {
+    pub set_from_optional_any: bool,
+    pub conditions: Option<Vec<ConditionVariant>>,
+    // 各 manipulator には、その動作に関わる項目だけを付ける (relevant_parameters)
+    pub parameters: Parameters,
+    pub simultaneous_options: SimultaneousSettings,
+    pub shift_variant_policy: ShiftVariantPolicy,
+    pub typing: TypingEnvironment,
+}
+
+pub fn input_source_if_conditions(input_source_ids: &[String]) -> Option<Vec<ConditionVariant>> {
+    if input_source_ids.is_empty() {
+        return None;
+    }
+    Some(vec![ConditionVariant::InputSourceIf {
+        input_sources: input_source_ids
+            .iter()
+            .map(|id| InputSourceDetail {
+                input_source_id: Some(id.clone()),
+            })
+            .collect(),
+    }])
+}
+
+// frontmost_application_if / unless の対象。bundle identifier とファイルパスは正規表現で書く。
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct ApplicationFilter {
+    #[serde(default)]
+    pub if_bundle_identifiers: Vec<String>,
+    #[serde(default)]
+    pub if_file_paths: Vec<String>,
+    #[serde(default)]
+    pub unless_bundle_identifiers: Vec<String>,
+    #[serde(default)]
+    pub unless_file_paths: Vec<String>,
+}
+
+impl ApplicationFilter {
+    // マッピングファイル中の `key=value` 形式のオプションを反映する。対象外のキーなら false を返す。
+    pub fn apply_option(&mut self, key: &str, value: &str) -> bool {
+        let list = match key {
+            "if_bundle_identifiers" => &mut self.if_bundle_identifiers,
+            "if_file_paths" => &mut self.if_file_paths,
+            "unless_bundle_identifiers" => &mut self.unless_bundle_identifiers,
+            "unless_file_paths" => &mut self.unless_file_paths,
+            _ => return false,
+        };
+        list.push(value.to_string());
+        true
+    }
+
+    pub fn conditions(&self) -> Vec<ConditionVariant> {
+        let mut conditions = Vec::new();
+        if !self.if_bundle_identifiers.is_empty() || !self.if_file_paths.is_empty() {
+            conditions.push(ConditionVariant::FrontmostApplicationIf {
+                bundle_identifiers: self.if_bundle_identifiers.clone(),
+                file_paths: self.if_file_paths.clone(),
+            });
+        }
+        if !self.unless_bundle_identifiers.is_empty() || !self.unless_file_paths.is_empty() {
+            conditions.push(ConditionVariant::FrontmostApplicationUnless {
+                bundle_identifiers: self.unless_bundle_identifiers.clone(),
+                file_paths: self.unless_file_paths.clone(),
+            });
+        }
+        conditions
+    }
+}
+
+// マッピングファイル中の `key=value` 形式で parameters の項目を指定する。対象外のキーなら false を返す。
+// キーは Karabiner の名前から basic. を除いたもの (to_if_alone_timeout_milliseconds など)。
+pub fn apply_parameter_option(
+    parameters: &mut Parameters,
+    key: &str,
+    value: &str,
+) -> Result<bool, String> {
+    let field = match key.strip_prefix("basic.").unwrap_or(key) {
+        "to_if_alone_timeout_milliseconds" => &mut parameters.to_if_alone_timeout_milliseconds,
+        "to_if_held_down_threshold_milliseconds" => {
+            &mut parameters.to_if_held_down_threshold_milliseconds
+        }
+        "to_delayed_action_delay_milliseconds" => {
+            &mut parameters.to_delayed_action_delay_milliseconds
+        }
+        "simultaneous_threshold_milliseconds" => {
+            &mut parameters.simultaneous_threshold_milliseconds
+        }
+        _ => return Ok(false),
+    };
+    *field = Some(value.parse().map_err(|_| {
+        format!(
+            "Invalid {} '{}' (expected a number of milliseconds)",
+            key, value
+        )
+    })?);
+    Ok(true)
+}
+
+// 設定された parameters のうち、manipulator の動作に関わる項目だけを残す。
+// to_delayed_action はこのツールでは生成しないため、その遅延は指定されていればそのまま付ける。
+fn relevant_parameters(parameters: &Parameters, manipulator: &Manipulator) -> Option<Parameters> {
+    let relevant = Parameters {
+        to_if_alone_timeout_milliseconds: parameters
+            .to_if_alone_timeout_milliseconds
+            .filter(|_| !manipulator.to_if_alone.is_empty()),
+        to_if_held_down_threshold_milliseconds: parameters
+            .to_if_held_down_threshold_milliseconds
+            .filter(|_| !manipulator.to_if_held_down.is_empty()),
+        to_delayed_action_delay_milliseconds: parameters.to_delayed_action_delay_milliseconds,
+        simultaneous_threshold_milliseconds: parameters
+            .simultaneous_threshold_milliseconds
+            .filter(|_| manipulator.from.simultaneous.is_some()),
+    };
+    (relevant != Parameters::default()).then_some(relevant)
+}
+
+// 同時押しの manipulator の from.simultaneous_options。ルール全体・セクション・同時押しごとに書け、
+// より狭い範囲の指定が優先される。to_after_key_up は出力の文字列のまま持ち、manipulator の生成時に変換する。
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct SimultaneousSettings {
+    #[serde(default)]
+    pub detect_key_down_uninterruptedly: Option<bool>,
+    #[serde(default)]
+    pub key_down_order: Option<KeyOrder>,
+    #[serde(default)]
+    pub key_up_order: Option<KeyOrder>,
+    #[serde(default)]
+    pub key_up_when: Option<KeyUpWhen>,
+    #[serde(default)]
+    pub to_after_key_up: Option<String>,
+}
+
+impl SimultaneousSettings {
+    // `key=value` 形式のオプションを反映する。対象外のキーなら false を返す。
+    // order は key_down_order の短縮形。
+    pub fn apply_option(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        let invalid =
+            |expected: &str| format!("Invalid {} '{}' (expected {})", key, value, expected);
+        let key_order = || {
+            KeyOrder::from_name(value)
+                .ok_or_else(|| invalid("insensitive, strict or strict_inverse"))
+        };
+        match key {
+            "detect_key_down_uninterruptedly" => {
+                self.detect_key_down_uninterruptedly =
+                    Some(value.parse().map_err(|_| invalid("true or false"))?)
             }
-            FromEventType::Simultaneous => {
-                if let Some(keys) = parsed_from_event.simultaneous_keys {
-                    from_object_for_manipulator.simultaneous = Some(
-                        keys.into_iter()
-                            .map(|kc| SimultaneousKey { key_code: kc })
-                            .collect(),
-                    );
-                }
+            "order" | "key_down_order" => self.key_down_order = Some(key_order()?),
+            "key_up_order" => self.key_up_order = Some(key_order()?),
+            "key_up_when" => {
+                self.key_up_when =
+                    Some(KeyUpWhen::from_name(value).ok_or_else(|| invalid("any or all"))?)
+            }
+            "to_after_key_up" => self.to_after_key_up = Some(value.to_string()),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // `simul(k q; order=strict)` の ; 以降のように、同時押しのオプションだけが並んだ文字列
+    pub fn parse(options: &str, context: &str) -> Result<SimultaneousSettings, String> {
+        let mut settings = SimultaneousSettings::default();
+        for (key, value) in parse_option_tokens(options, context)? {
+            if !settings
+                .apply_option(key, value)
+                .map_err(|message| format!("{} in '{}'", message, context))?
+            {
+                return Err(format!(
+                    "Unknown simultaneous option '{}' in '{}'",
+                    key, context
+                ));
             }
         }
+        Ok(settings)
+    }
 
-        let to_input_str: &str = to_input_str_ref;
-        let to_transformed_key: TransformedToKey = transform_string_for_to_event(to_input_str);
-        let mut to_events_for_manipulator: Vec<ToEvent> = Vec::new();
+    // other で指定された項目を優先して重ねる
+    pub fn overridden_by(&self, other: &SimultaneousSettings) -> SimultaneousSettings {
+        SimultaneousSettings {
+            detect_key_down_uninterruptedly: other
+                .detect_key_down_uninterruptedly
+                .or(self.detect_key_down_uninterruptedly),
+            key_down_order: other.key_down_order.or(self.key_down_order),
+            key_up_order: other.key_up_order.or(self.key_up_order),
+            key_up_when: other.key_up_when.or(self.key_up_when),
+            to_after_key_up: other
+                .to_after_key_up
+                .clone()
+                .or_else(|| self.to_after_key_up.clone()),
+        }
+    }
 
-        let key_code_str_from_transform = to_transformed_key.key_code.clone();
-        let modifires_from_transform = if to_transformed_key.mandatory_modifiers.is_empty() {
-            None
+    fn build(&self, env: &TypingEnvironment) -> Option<SimultaneousOptions> {
+        if *self == SimultaneousSettings::default() {
+            return None;
+        }
+        Some(SimultaneousOptions {
+            detect_key_down_uninterruptedly: self.detect_key_down_uninterruptedly,
+            key_down_order: self.key_down_order,
+            key_up_order: self.key_up_order,
+            key_up_when: self.key_up_when,
+            to_after_key_up: build_optional_to_events(&self.to_after_key_up, false, env),
+        })
+    }
+}
+
+// ルール全体に付ける条件 (入力ソース・最前面のアプリケーション・デバイス)
+pub fn rule_conditions(
+    input_source_ids: &[String],
+    applications: &ApplicationFilter,
+    devices: Vec<ConditionVariant>,
+) -> Option<Vec<ConditionVariant>> {
+    let mut conditions = input_source_if_conditions(input_source_ids).unwrap_or_default();
+    conditions.extend(applications.conditions());
+    conditions.extend(devices);
+    if conditions.is_empty() {
+        None
+    } else {
+        Some(conditions)
+    }
+}
+
+// 1 つの入力から条件を変えて生成するルールの変種 (デバイスごと・ホストの配列ごと)。
+// labels はルールの description に付け加えて区別する。
+#[derive(Debug, Default, Clone)]
+pub struct RuleVariant {
+    pub labels: Vec<String>,
+    pub conditions: Vec<ConditionVariant>,
+    pub host_layout: Option<HostLayout>,
+}
+
+impl RuleVariant {
+    pub fn describe(&self, description: &str) -> String {
+        if self.labels.is_empty() {
+            description.to_string()
         } else {
-            Some(to_transformed_key.mandatory_modifiers.clone())
-        };
+            format!("{} ({})", description, self.labels.join(", "))
+        }
+    }
+
+    pub fn apply(&self, options: &RuleOptions) -> RuleOptions {
+        let mut options = with_conditions(options, self.conditions.clone());
+        if let Some(layout) = self.host_layout {
+            options.typing.layout = layout;
+        }
+        options
+    }
+}
+
+// 2 種類の変種のすべての組み合わせ
+pub fn combine_variants(first: &[RuleVariant], second: &[RuleVariant]) -> Vec<RuleVariant> {
+    first
+        .iter()
+        .flat_map(|a| {
+            second.iter().map(move |b| RuleVariant {
+                labels: [a.labels.clone(), b.labels.clone()].concat(),
+                conditions: [a.conditions.clone(), b.conditions.clone()].concat(),
+                host_layout: b.host_layout.or(a.host_layout),
+            })
+        })
+        .collect()
+}
+
+// 配列ごとの変種。複数の配列を指定した場合は keyboard_type_if で出し分ける。
+pub fn host_layout_variants(layouts: &[HostLayout], keyboard_type_if: bool) -> Vec<RuleVariant> {
+    if layouts.is_empty() {
+        return vec![RuleVariant::default()];
+    }
+    let multiple = layouts.len() > 1;
+    layouts
+        .iter()
+        .map(|&layout| RuleVariant {
+            labels: if multiple {
+                vec![layout.name().to_string()]
+            } else {
+                Vec::new()
+            },
+            conditions: if multiple || keyboard_type_if {
+                vec![layout.keyboard_type_if()]
+            } else {
+                Vec::new()
+            },
+            host_layout: Some(layout),
+        })
+        .collect()
+}
+
+pub fn generate_karabiner_config(title: Option<String>, rules: Vec<Rule>) -> File {
+    File { title, rules }
+}
+
+fn build_from_object(
+    parsed_from_event: &ParsedFromEvent,
+    options: &RuleOptions,
+) -> crate::json_structures::From {
+    let mut from_object_for_manipulator = crate::json_structures::From::default();
+
+    match parsed_from_event.event_type {
+        FromEventType::SingleKey => {
+            from_object_for_manipulator.key_code = parsed_from_event.key_code.clone();
+            let mandatory_mods = parsed_from_event.modifiers.clone();
+            let mut optional_mods = parsed_from_event.optional_modifiers.clone();
+            if options.set_from_optional_any && !optional_mods.contains(&Modifier::Any) {
+                optional_mods.push(Modifier::Any);
+            }
 
-        let is_romaji_sequence_to = key_code_str_from_transform.len() > 1
-            && key_code_str_from_transform
-                .chars()
-                .all(|c| c.is_ascii_lowercase())
-            && !is_known_single_multichar_keycode(&key_code_str_from_transform);
-
-        if is_romaji_sequence_to {
-            for char_in_sequence in key_code_str_from_transform.chars() {
-                to_events_for_manipulator.push(ToEvent {
-                    key_code: Some(char_in_sequence.to_string()),
-                    modifiers: modifires_from_transform.clone(),
-                    ..Default::default()
+            if !mandatory_mods.is_empty() || !optional_mods.is_empty() {
+                from_object_for_manipulator.modifiers = Some(Modifiers {
+                    mandatory: mandatory_mods,
+                    optional: optional_mods,
                 });
             }
-        } else {
-            to_events_for_manipulator.push(ToEvent {
-                key_code: Some(key_code_str_from_transform.clone()),
-                modifiers: modifires_from_transform.clone(),
-                ..Default::default()
-            });
         }
+        FromEventType::Simultaneous => {
+            if let Some(keys) = parsed_from_event.simultaneous_keys.clone() {
+                from_object_for_manipulator.simultaneous = Some(
+                    keys.into_iter()
+                        .map(|kc| SimultaneousKey { key_code: kc })
+                        .collect(),
+                );
+            }
+            // 同時押しに書いたオプションの誤りは validate_mappings で報告する
+            let chord_settings = parsed_from_event
+                .simultaneous_options
+                .as_deref()
+                .and_then(|chord| SimultaneousSettings::parse(chord, chord).ok())
+                .unwrap_or_default();
+            from_object_for_manipulator.simultaneous_options = options
+                .simultaneous_options
+                .overridden_by(&chord_settings)
+                .build(&options.typing);
+        }
+    }
+    from_object_for_manipulator
+}
 
-        final_manipulators.push(Manipulator {
-            from: from_object_for_manipulator.clone(),
-            to: to_events_for_manipulator.clone(),
-            r#type: "basic".to_string(),
-            conditions: global_manipulator_conditions.clone(),
-        });
+// 1 つのマッピングから生成される manipulator (Shift 付きの変種を含む)
+pub fn generate_manipulators(mapping: &Mapping, options: &RuleOptions) -> Vec<Manipulator> {
+    let mut final_manipulators: Vec<Manipulator> = Vec::new();
+
+    let from_input_str: &str = &mapping.from;
+    let parsed_from_event: ParsedFromEvent =
+        parse_from_input_string(from_input_str, &options.typing);
+    let from_object_for_manipulator = build_from_object(&parsed_from_event, options);
+
+    let to_input_str: &str = &mapping.to;
+    let parsed_to_event: ParsedToEvent = parse_to_input_string(to_input_str);
+    final_manipulators.push(Manipulator {
+        from: from_object_for_manipulator.clone(),
+        to: build_optional_to_events(&parsed_to_event.to, false, &options.typing),
+        to_if_alone: build_optional_to_events(&parsed_to_event.to_if_alone, false, &options.typing),
+        to_if_held_down: build_optional_to_events(
+            &parsed_to_event.to_if_held_down,
+            false,
+            &options.typing,
+        ),
+        to_after_key_up: Vec::new(),
+        r#type: "basic".to_string(),
+        conditions: options.conditions.clone(),
+        parameters: None,
+    });
 
-	        let should_create_shifted_variant = match parsed_from_event.event_type {
+    let should_create_shifted_variant = match options.shift_variant_policy {
+        ShiftVariantPolicy::Never => false,
+        ShiftVariantPolicy::Auto => match parsed_from_event.event_type {
             FromEventType::SingleKey => {
                 from_input_str.len() == 1 && from_input_str.chars().all(|c| c.is_ascii_lowercase())
             }
-            FromEventType::Simultaneous => {
-                true
-            }
+            FromEventType::Simultaneous => true,
+        },
+    };
+
+    if should_create_shifted_variant {
+        let mut shifted_from_object = from_object_for_manipulator;
+        let mut mods = shifted_from_object.modifiers.take().unwrap_or_default();
+        mods.mandatory = add_left_shift(&mods.mandatory);
+        shifted_from_object.modifiers = Some(mods);
+
+        final_manipulators.push(Manipulator {
+            from: shifted_from_object,
+            to: build_optional_to_events(&parsed_to_event.to, true, &options.typing),
+            to_if_alone: build_optional_to_events(
+                &parsed_to_event.to_if_alone,
+                true,
+                &options.typing,
+            ),
+            to_if_held_down: build_optional_to_events(
+                &parsed_to_event.to_if_held_down,
+                true,
+                &options.typing,
+            ),
+            to_after_key_up: Vec::new(),
+            r#type: "basic".to_string(),
+            conditions: options.conditions.clone(),
+            parameters: None,
+        });
+    }
+    // tap/hold を含むマッピングにだけ to_if_alone / to_if_held_down の閾値を付ける
+    for manipulator in &mut final_manipulators {
+        manipulator.parameters = relevant_parameters(&options.parameters, manipulator);
+    }
+    final_manipulators
+}
+
+fn set_variable_event(name: &str, value: i64) -> ToEvent {
+    ToEvent {
+        set_variable: Some(SetVariable {
+            name: name.to_string(),
+            value: VariableValue::Int(value),
+        }),
+        ..Default::default()
+    }
+}
+
+pub fn with_conditions(options: &RuleOptions, extra: Vec<ConditionVariant>) -> RuleOptions {
+    if extra.is_empty() {
+        return options.clone();
+    }
+    let mut conditions = options.conditions.clone().unwrap_or_default();
+    conditions.extend(extra);
+    RuleOptions {
+        conditions: Some(conditions),
+        ..options.clone()
+    }
+}
+
+// レイヤーを有効・無効にするトリガーキーの manipulator
+fn generate_layer_trigger_manipulators(
+    layer: &LayerSpec,
+    options: &RuleOptions,
+) -> Vec<Manipulator> {
+    let parsed_trigger = parse_from_input_string(&layer.trigger, &options.typing);
+    let from = build_from_object(&parsed_trigger, options);
+    let active = || VariableValue::Int(1);
+    let manipulator = |to: Vec<ToEvent>, conditions: Option<Vec<ConditionVariant>>| {
+        let mut manipulator = Manipulator {
+            from: from.clone(),
+            to,
+            to_if_alone: Vec::new(),
+            to_if_held_down: Vec::new(),
+            to_after_key_up: Vec::new(),
+            r#type: "basic".to_string(),
+            conditions,
+            parameters: None,
         };
+        manipulator.parameters = relevant_parameters(&options.parameters, &manipulator);
+        manipulator
+    };
+    match layer.mode {
+        LayerMode::Hold => {
+            let mut trigger = manipulator(
+                vec![set_variable_event(&layer.variable, 1)],
+                options.conditions.clone(),
+            );
+            trigger.to_after_key_up = vec![set_variable_event(&layer.variable, 0)];
+            if let Some(tap) = &layer.tap {
+                trigger.to_if_alone = build_to_events(tap, false, &options.typing);
+                trigger.parameters = relevant_parameters(&options.parameters, &trigger);
+            }
+            vec![trigger]
+        }
+        // 有効な時に押したら無効にする manipulator を先に置く
+        LayerMode::Toggle => vec![
+            manipulator(
+                vec![set_variable_event(&layer.variable, 0)],
+                with_conditions(
+                    options,
+                    vec![ConditionVariant::VariableIf {
+                        name: layer.variable.clone(),
+                        value: active(),
+                    }],
+                )
+                .conditions,
+            ),
+            manipulator(
+                vec![set_variable_event(&layer.variable, 1)],
+                with_conditions(
+                    options,
+                    vec![ConditionVariant::VariableUnless {
+                        name: layer.variable.clone(),
+                        value: active(),
+                    }],
+                )
+                .conditions,
+            ),
+        ],
+    }
+}
+
+// マッピング自身に書かれた条件をセクションの条件に加えて manipulator を生成する
+fn generate_section_manipulators(mapping: &Mapping, options: &RuleOptions) -> Vec<Manipulator> {
+    generate_manipulators(
+        mapping,
+        &with_conditions(options, mapping.applications.conditions()),
+    )
+}
 
-        if should_create_shifted_variant {
-	    let mut shifted_from_object = from_object_for_manipulator;
-	                let mut mods = shifted_from_object.modifiers.take().unwrap_or_default();
-            mods.mandatory = add_left_shift(&mods.mandatory);
-            shifted_from_object.modifiers = Some(mods);
-
-	                let to_transformed_key = transform_string_for_to_event(to_input_str); // 再度取得
-            let is_romaji_sequence_to = to_transformed_key.key_code.len() > 1 &&
-                                        to_transformed_key.key_code.chars().all(|c| c.is_ascii_lowercase()) &&
-                                        !is_known_single_multichar_keycode(&to_transformed_key.key_code);
-            let mut to_shifted_events: Vec<ToEvent> = Vec::new();
-            let to_shifted_overall_modifiers = add_left_shift(&to_transformed_key.mandatory_modifiers);
-	    
-            if is_romaji_sequence_to {
-                for char_in_sequence in key_code_str_from_transform.chars() {
-                    to_shifted_events.push(ToEvent {
-                        key_code: Some(char_in_sequence.to_string()),
-                        modifiers: Some(to_shifted_overall_modifiers.clone()),
-                        ..Default::default()
-                    });
+// マッピングごと (レイヤーの見出しを含む) に生成される manipulator の組。
+// Karabiner は最初に一致した manipulator を使うため、レイヤーのトリガーとレイヤー内の
+// マッピングを通常のマッピングより先に並べる。
+pub fn generate_manipulator_groups<'a>(
+    mappings: &'a [Mapping],
+    options: &RuleOptions,
+) -> Vec<(&'a Mapping, Vec<Manipulator>)> {
+    let (sections, _) = split_into_sections(mappings);
+    let mut layer_groups = Vec::new();
+    let mut plain_groups = Vec::new();
+    for section in &sections {
+        let mut section_options = with_conditions(options, section.applications.conditions());
+        section_options.parameters = options.parameters.overridden_by(&section.parameters);
+        section_options.simultaneous_options = options
+            .simultaneous_options
+            .overridden_by(&section.simultaneous_options);
+        match &section.kind {
+            SectionKind::Layer(layer) => {
+                if let Some(header) = section.header {
+                    layer_groups.push((
+                        header,
+                        generate_layer_trigger_manipulators(layer, &section_options),
+                    ));
+                }
+                let layer_options = with_conditions(
+                    &section_options,
+                    vec![ConditionVariant::VariableIf {
+                        name: layer.variable.clone(),
+                        value: VariableValue::Int(1),
+                    }],
+                );
+                for mapping in &section.mappings {
+                    layer_groups.push((
+                        *mapping,
+                        generate_section_manipulators(mapping, &layer_options),
+                    ));
                 }
-            } else {
-                to_shifted_events.push(ToEvent {
-                    key_code: Some(key_code_str_from_transform.clone()),
-                    modifiers: Some(to_shifted_overall_modifiers.clone()),
-                    ..Default::default()
-                });
             }
+            SectionKind::Plain => {
+                for mapping in &section.mappings {
+                    plain_groups.push((
+                        *mapping,
+                        generate_section_manipulators(mapping, &section_options),
+                    ));
+                }
+            }
+        }
+    }
+    layer_groups.extend(plain_groups);
+    layer_groups
+}
 
-            final_manipulators.push(Manipulator {
-                from: shifted_from_object,
-                to: to_shifted_events,
-                r#type: "basic".to_string(),
-                conditions: global_manipulator_conditions.clone(),
-            });
+pub fn generate_karabiner_rule(
+    description: String,
+    mappings_to_process: &[Mapping],
+    options: &RuleOptions,
+) -> Rule {
+    Rule {
+        description,
+        enabled: None,
+        make_karabiner_id: None,
+        manipulators: generate_manipulator_groups(mappings_to_process, options)
+            .into_iter()
+            .flat_map(|(_, manipulators)| manipulators)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn mapping(from: &str, to: &str) -> Mapping {
+        Mapping {
+            from: from.to_string(),
+            to: to.to_string(),
+            applications: ApplicationFilter::default(),
+            location: None,
         }
     }
-    File {
-        rules: vec![Rule {
-            description,
-            manipulators: final_manipulators,
-        }],
+
+    fn conditions_of(manipulator: &Manipulator) -> serde_json::Value {
+        serde_json::to_value(&manipulator.conditions).unwrap()
+    }
+
+    #[test]
+    fn application_conditions_are_emitted() {
+        let applications = ApplicationFilter {
+            if_bundle_identifiers: vec!["^com\\.apple\\.Terminal$".to_string()],
+            unless_file_paths: vec!["/vim$".to_string()],
+            ..Default::default()
+        };
+        let conditions = rule_conditions(&[], &applications, Vec::new());
+        assert_eq!(
+            serde_json::to_value(conditions).unwrap(),
+            json!([
+                {
+                    "type": "frontmost_application_if",
+                    "bundle_identifiers": ["^com\\.apple\\.Terminal$"]
+                },
+                { "type": "frontmost_application_unless", "file_paths": ["/vim$"] }
+            ])
+        );
+        assert!(rule_conditions(&[], &ApplicationFilter::default(), Vec::new()).is_none());
+    }
+
+    // マッピング自身の条件はセクションの条件の後ろに付く
+    #[test]
+    fn mapping_conditions_follow_section_conditions() {
+        let mut guarded = mapping("j", "k");
+        guarded.applications.if_bundle_identifiers = vec!["^com\\.example$".to_string()];
+        let mappings = [
+            mapping(
+                "[section base]",
+                "unless_bundle_identifiers=^com\\.apple\\.Terminal$",
+            ),
+            guarded,
+        ];
+        let rule = generate_karabiner_rule("test".to_string(), &mappings, &RuleOptions::default());
+        assert_eq!(
+            conditions_of(&rule.manipulators[0]),
+            json!([
+                {
+                    "type": "frontmost_application_unless",
+                    "bundle_identifiers": ["^com\\.apple\\.Terminal$"]
+                },
+                { "type": "frontmost_application_if", "bundle_identifiers": ["^com\\.example$"] }
+            ])
+        );
+    }
+
+    #[test]
+    fn layer_variable_conditions_are_emitted() {
+        let mappings = [
+            mapping("[layer nav]", "trigger=caps_lock mode=toggle"),
+            mapping("h", "left_arrow"),
+        ];
+        let rule = generate_karabiner_rule("test".to_string(), &mappings, &RuleOptions::default());
+        let active = json!([{ "type": "variable_if", "name": "layer_nav", "value": 1 }]);
+        let inactive = json!([{ "type": "variable_unless", "name": "layer_nav", "value": 1 }]);
+        // トグルのトリガーは 有効なら無効に・無効なら有効に の順で、レイヤー内のマッピングは有効な時だけ使う
+        assert_eq!(conditions_of(&rule.manipulators[0]), active);
+        assert_eq!(conditions_of(&rule.manipulators[1]), inactive);
+        assert!(rule.manipulators.len() > 2);
+        for manipulator in &rule.manipulators[2..] {
+            assert_eq!(conditions_of(manipulator), active);
+        }
+    }
+
+    // --disabled は rule.enabled を Some(false) にする。指定しなければ enabled は書かない。
+    #[test]
+    fn asset_has_title_and_enabled_only_when_disabled() {
+        let mappings = [mapping("j", "k")];
+        let options = RuleOptions::default();
+        let enabled = generate_karabiner_rule("enabled".to_string(), &mappings, &options);
+        let mut disabled = generate_karabiner_rule("disabled".to_string(), &mappings, &options);
+        disabled.enabled = Some(false);
+        let file =
+            generate_karabiner_config(Some("My layout".to_string()), vec![enabled, disabled]);
+        let value = serde_json::to_value(&file).unwrap();
+
+        assert_eq!(value["title"], "My layout");
+        assert!(value["rules"][0].get("enabled").is_none());
+        assert_eq!(value["rules"][1]["enabled"], false);
+
+        let untitled = serde_json::to_value(generate_karabiner_config(None, Vec::new())).unwrap();
+        assert!(untitled.get("title").is_none());
+    }
+
+    fn parameters_of(manipulator: &Manipulator) -> serde_json::Value {
+        serde_json::to_value(&manipulator.parameters).unwrap()
+    }
+
+    // 4 つの閾値をすべて指定しても、各 manipulator には動作に関わるものだけが付く
+    #[test]
+    fn parameters_are_kept_only_where_they_apply() {
+        let options = RuleOptions {
+            parameters: Parameters {
+                to_if_alone_timeout_milliseconds: Some(200),
+                to_if_held_down_threshold_milliseconds: Some(300),
+                to_delayed_action_delay_milliseconds: Some(400),
+                simultaneous_threshold_milliseconds: Some(50),
+            },
+            shift_variant_policy: ShiftVariantPolicy::Never,
+            ..Default::default()
+        };
+        let parameters = |from: &str, to: &str| {
+            parameters_of(&generate_manipulators(&mapping(from, to), &options)[0])
+        };
+        assert_eq!(
+            parameters("j", "k"),
+            json!({ "basic.to_delayed_action_delay_milliseconds": 400 })
+        );
+        assert_eq!(
+            parameters("j", "left_shift tap(k)"),
+            json!({
+                "basic.to_if_alone_timeout_milliseconds": 200,
+                "basic.to_delayed_action_delay_milliseconds": 400
+            })
+        );
+        assert_eq!(
+            parameters("j", "k hold(l)"),
+            json!({
+                "basic.to_if_held_down_threshold_milliseconds": 300,
+                "basic.to_delayed_action_delay_milliseconds": 400
+            })
+        );
+        assert_eq!(
+            parameters("simul(j k)", "l"),
+            json!({
+                "basic.to_delayed_action_delay_milliseconds": 400,
+                "basic.simultaneous_threshold_milliseconds": 50
+            })
+        );
+    }
+
+    #[test]
+    fn parameters_are_omitted_when_none_apply() {
+        let options = RuleOptions {
+            parameters: Parameters {
+                to_if_alone_timeout_milliseconds: Some(200),
+                to_if_held_down_threshold_milliseconds: Some(300),
+                simultaneous_threshold_milliseconds: Some(50),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        for manipulator in generate_manipulators(&mapping("j", "k"), &options) {
+            assert!(manipulator.parameters.is_none());
+        }
+        // tap だけのレイヤーのトリガーには長押しの閾値を付けない
+        let mappings = [mapping(
+            "[layer nav]",
+            "trigger=caps_lock mode=hold tap=escape",
+        )];
+        let rule = generate_karabiner_rule("test".to_string(), &mappings, &options);
+        assert_eq!(
+            parameters_of(&rule.manipulators[0]),
+            json!({ "basic.to_if_alone_timeout_milliseconds": 200 })
+        );
     }
 }
@@ -0,0 +1,350 @@
+// Karabiner-Elements が受け付けるキー名の一覧。
+// 生成した設定に含まれる名前がこれらに含まれない場合、Karabiner は黙ってそのイベントを無視する。
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// KeyCode 列挙型と Karabiner 上の名前との対応を 1 か所で定義する
+macro_rules! key_codes {
+    ($($(#[$meta:meta])* $variant:ident => $name:literal,)*) => {
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        pub enum KeyCode {
+            $($(#[$meta])* $variant,)*
+            // 一覧にないキー名をそのまま出力するための逃げ道。検査で警告される。
+            Other(String),
+        }
+
+        impl KeyCode {
+            pub fn name(&self) -> &str {
+                match self {
+                    $(KeyCode::$variant => $name,)*
+                    KeyCode::Other(name) => name,
+                }
+            }
+
+            pub fn from_name(name: &str) -> KeyCode {
+                match name {
+                    $($name => KeyCode::$variant,)*
+                    other => KeyCode::Other(other.to_string()),
+                }
+            }
+        }
+    };
+}
+
+key_codes! {
+    // 英字
+    A => "a",
+    B => "b",
+    C => "c",
+    D => "d",
+    E => "e",
+    F => "f",
+    G => "g",
+    H => "h",
+    I => "i",
+    J => "j",
+    K => "k",
+    L => "l",
+    M => "m",
+    N => "n",
+    O => "o",
+    P => "p",
+    Q => "q",
+    R => "r",
+    S => "s",
+    T => "t",
+    U => "u",
+    V => "v",
+    W => "w",
+    X => "x",
+    Y => "y",
+    Z => "z",
+    // 数字
+    Num1 => "1",
+    Num2 => "2",
+    Num3 => "3",
+    Num4 => "4",
+    Num5 => "5",
+    Num6 => "6",
+    Num7 => "7",
+    Num8 => "8",
+    Num9 => "9",
+    Num0 => "0",
+    // 制御キー
+    ReturnOrEnter => "return_or_enter",
+    Escape => "escape",
+    DeleteOrBackspace => "delete_or_backspace",
+    DeleteForward => "delete_forward",
+    Tab => "tab",
+    Spacebar => "spacebar",
+    CapsLock => "caps_lock",
+    // 記号
+    Hyphen => "hyphen",
+    EqualSign => "equal_sign",
+    OpenBracket => "open_bracket",
+    CloseBracket => "close_bracket",
+    Backslash => "backslash",
+    NonUsPound => "non_us_pound",
+    Semicolon => "semicolon",
+    Quote => "quote",
+    GraveAccentAndTilde => "grave_accent_and_tilde",
+    Comma => "comma",
+    Period => "period",
+    Slash => "slash",
+    NonUsBackslash => "non_us_backslash",
+    // カーソル移動
+    UpArrow => "up_arrow",
+    DownArrow => "down_arrow",
+    LeftArrow => "left_arrow",
+    RightArrow => "right_arrow",
+    PageUp => "page_up",
+    PageDown => "page_down",
+    Home => "home",
+    End => "end",
+    // 修飾キー
+    LeftControl => "left_control",
+    LeftShift => "left_shift",
+    LeftOption => "left_option",
+    LeftCommand => "left_command",
+    RightControl => "right_control",
+    RightShift => "right_shift",
+    RightOption => "right_option",
+    RightCommand => "right_command",
+    Fn => "fn",
+    // ファンクションキー
+    F1 => "f1",
+    F2 => "f2",
+    F3 => "f3",
+    F4 => "f4",
+    F5 => "f5",
+    F6 => "f6",
+    F7 => "f7",
+    F8 => "f8",
+    F9 => "f9",
+    F10 => "f10",
+    F11 => "f11",
+    F12 => "f12",
+    F13 => "f13",
+    F14 => "f14",
+    F15 => "f15",
+    F16 => "f16",
+    F17 => "f17",
+    F18 => "f18",
+    F19 => "f19",
+    F20 => "f20",
+    F21 => "f21",
+    F22 => "f22",
+    F23 => "f23",
+    F24 => "f24",
+    // メディアキー
+    DisplayBrightnessDecrement => "display_brightness_decrement",
+    DisplayBrightnessIncrement => "display_brightness_increment",
+    MissionControl => "mission_control",
+    Launchpad => "launchpad",
+    Dashboard => "dashboard",
+    IlluminationDecrement => "illumination_decrement",
+    IlluminationIncrement => "illumination_increment",
+    Rewind => "rewind",
+    PlayOrPause => "play_or_pause",
+    Fastforward => "fastforward",
+    Mute => "mute",
+    VolumeDecrement => "volume_decrement",
+    VolumeIncrement => "volume_increment",
+    Eject => "eject",
+    AppleDisplayBrightnessDecrement => "apple_display_brightness_decrement",
+    AppleDisplayBrightnessIncrement => "apple_display_brightness_increment",
+    AppleTopCaseDisplayBrightnessDecrement => "apple_top_case_display_brightness_decrement",
+    AppleTopCaseDisplayBrightnessIncrement => "apple_top_case_display_brightness_increment",
+    // テンキー
+    KeypadNumLock => "keypad_num_lock",
+    KeypadSlash => "keypad_slash",
+    KeypadAsterisk => "keypad_asterisk",
+    KeypadHyphen => "keypad_hyphen",
+    KeypadPlus => "keypad_plus",
+    KeypadEnter => "keypad_enter",
+    Keypad1 => "keypad_1",
+    Keypad2 => "keypad_2",
+    Keypad3 => "keypad_3",
+    Keypad4 => "keypad_4",
+    Keypad5 => "keypad_5",
+    Keypad6 => "keypad_6",
+    Keypad7 => "keypad_7",
+    Keypad8 => "keypad_8",
+    Keypad9 => "keypad_9",
+    Keypad0 => "keypad_0",
+    KeypadPeriod => "keypad_period",
+    KeypadEqualSign => "keypad_equal_sign",
+    KeypadComma => "keypad_comma",
+    // 日本語・国際キー
+    JapaneseEisuu => "japanese_eisuu",
+    JapaneseKana => "japanese_kana",
+    JapanesePcNfer => "japanese_pc_nfer",
+    JapanesePcXfer => "japanese_pc_xfer",
+    JapanesePcKatakana => "japanese_pc_katakana",
+    International1 => "international1",
+    International2 => "international2",
+    International3 => "international3",
+    International4 => "international4",
+    International5 => "international5",
+    International6 => "international6",
+    International7 => "international7",
+    International8 => "international8",
+    International9 => "international9",
+    Lang1 => "lang1",
+    Lang2 => "lang2",
+    Lang3 => "lang3",
+    Lang4 => "lang4",
+    Lang5 => "lang5",
+    Lang6 => "lang6",
+    Lang7 => "lang7",
+    Lang8 => "lang8",
+    Lang9 => "lang9",
+    // その他
+    PrintScreen => "print_screen",
+    ScrollLock => "scroll_lock",
+    Pause => "pause",
+    Insert => "insert",
+    Application => "application",
+    Help => "help",
+    Power => "power",
+    Execute => "execute",
+    Menu => "menu",
+    Select => "select",
+    Stop => "stop",
+    Again => "again",
+    Undo => "undo",
+    Cut => "cut",
+    Copy => "copy",
+    Paste => "paste",
+    Find => "find",
+    VkNone => "vk_none",
+}
+
+impl KeyCode {
+    pub fn is_known(&self) -> bool {
+        !matches!(self, KeyCode::Other(_))
+    }
+}
+
+impl std::fmt::Display for KeyCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Serialize for KeyCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(KeyCode::from_name(&name))
+    }
+}
+
+pub const CONSUMER_KEY_CODES: &[&str] = &[
+    "power",
+    "display_brightness_increment",
+    "display_brightness_decrement",
+    "fast_forward",
+    "rewind",
+    "scan_next_track",
+    "scan_previous_track",
+    "eject",
+    "play_or_pause",
+    "mute",
+    "volume_increment",
+    "volume_decrement",
+    "menu",
+    "dictation",
+    "ac_search",
+    "ac_home",
+    "ac_back",
+    "ac_forward",
+    "ac_stop",
+    "ac_refresh",
+    "ac_bookmarks",
+    "al_consumer_control_configuration",
+    "al_word_processor",
+    "al_text_editor",
+    "al_spreadsheet",
+    "al_graphics_editor",
+    "al_presentation_app",
+    "al_database_app",
+    "al_email_reader",
+    "al_voicemail",
+    "al_contacts_or_address_book",
+    "al_calendar_or_schedule",
+    "al_task_or_project_manager",
+    "al_log_or_journal_or_timecard",
+    "al_checkbook_or_finance",
+    "al_calculator",
+    "al_local_machine_browser",
+    "al_internet_browser",
+    "al_network_chat",
+    "al_terminal_lock_or_screensaver",
+    "al_keyboard_layout",
+    "voice_command",
+];
+
+pub const POINTING_BUTTONS: &[&str] = &[
+    "button1", "button2", "button3", "button4", "button5", "button6", "button7", "button8",
+    "button9", "button10", "button11", "button12", "button13", "button14", "button15", "button16",
+    "button17", "button18", "button19", "button20", "button21", "button22", "button23", "button24",
+    "button25", "button26", "button27", "button28", "button29", "button30", "button31", "button32",
+];
+
+// from.modifiers と to.modifiers に書ける名前
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Modifier {
+    Any,
+    CapsLock,
+    Command,
+    Control,
+    Fn,
+    Option,
+    Shift,
+    LeftCommand,
+    LeftControl,
+    LeftOption,
+    LeftShift,
+    RightCommand,
+    RightControl,
+    RightOption,
+    RightShift,
+}
+
+impl Modifier {
+    pub fn from_name(name: &str) -> Option<Modifier> {
+        match name {
+            "any" => Some(Modifier::Any),
+            "caps_lock" => Some(Modifier::CapsLock),
+            "command" => Some(Modifier::Command),
+            "control" => Some(Modifier::Control),
+            "fn" => Some(Modifier::Fn),
+            "option" => Some(Modifier::Option),
+            "shift" => Some(Modifier::Shift),
+            "left_command" => Some(Modifier::LeftCommand),
+            "left_control" => Some(Modifier::LeftControl),
+            "left_option" => Some(Modifier::LeftOption),
+            "left_shift" => Some(Modifier::LeftShift),
+            "right_command" => Some(Modifier::RightCommand),
+            "right_control" => Some(Modifier::RightControl),
+            "right_option" => Some(Modifier::RightOption),
+            "right_shift" => Some(Modifier::RightShift),
+            _ => None,
+        }
+    }
+}
+
+pub fn is_valid_consumer_key_code(name: &str) -> bool {
+    CONSUMER_KEY_CODES.contains(&name)
+}
+
+pub fn is_valid_pointing_button(name: &str) -> bool {
+    POINTING_BUTTONS.contains(&name)
+}
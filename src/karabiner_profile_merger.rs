@@ -0,0 +1,288 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::json_structures::File;
+
+#[derive(Debug)]
+pub enum MergeError {
+    FileReadError(String),
+    JsonError(String),
+    ProfileNotFound(String),
+}
+
+impl std::fmt::Display for MergeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MergeError::FileReadError(s) => write!(f, "File read error: {}", s),
+            MergeError::JsonError(s) => write!(f, "karabiner.json error: {}", s),
+            MergeError::ProfileNotFound(s) => write!(f, "Profile not found: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+// 生成したルールにつける目印の既定値 (--rule-id で指定できる)。入力ファイルのパスを
+// カレントディレクトリからの相対パスに正規化したもので、a/keys.rs と b/keys.rs を区別する。
+pub fn merge_rule_id(input_path: &str) -> String {
+    let base = std::env::current_dir().unwrap_or_default();
+    normalized_rule_id(Path::new(input_path), &base)
+}
+
+fn normalized_rule_id(input_path: &Path, base: &Path) -> String {
+    let normalize = |path: &Path| {
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !normalized.pop() {
+                        normalized.push("..");
+                    }
+                }
+                other => normalized.push(other),
+            }
+        }
+        normalized
+    };
+    let path = normalize(&base.join(input_path));
+    let base = normalize(base);
+    let relative = path.strip_prefix(&base).unwrap_or(&path);
+    relative.to_string_lossy().into_owned()
+}
+
+// マージした結果。kept_descriptions は生成したルールと description が同じで目印のない既存のルール
+// (ユーザーが書いたものかもしれないため、replace_by_description を指定しなければ残す)。
+pub struct MergedConfig {
+    pub json: String,
+    pub kept_descriptions: Vec<String>,
+}
+
+// 既存の karabiner.json を読み込み、指定したプロファイル (未指定なら選択中のもの) の
+// complex_modifications.rules のうち、生成したルールと同じ目印 (make_karabiner_id) を持つものを
+// すべて取り除き、最初に見つかった位置に生成したルールを入れる。見つからなければ末尾に追加する。
+// 目印のないルールは、replace_by_description なら description が一致するものも置き換える。
+// それ以外のルールや設定はキーの順序も含めてそのまま残す。
+pub fn merge_rules_into_karabiner_json(
+    config_path: &str,
+    profile_name: Option<&str>,
+    generated: &File,
+    replace_by_description: bool,
+) -> Result<MergedConfig, MergeError> {
+    let content = fs::read_to_string(config_path).map_err(|e| {
+        MergeError::FileReadError(format!("Failed to read file {}: {}", config_path, e))
+    })?;
+    // 型に読み込むと知らないキーが後ろに回るため、Value のまま必要な所だけ書き換える
+    let mut config: serde_json::Value = serde_json::from_str(&content)
+        .map_err(|e| MergeError::JsonError(format!("Failed to parse {}: {}", config_path, e)))?;
+
+    let profiles = config
+        .get_mut("profiles")
+        .and_then(|profiles| profiles.as_array_mut())
+        .ok_or_else(|| MergeError::JsonError(format!("{} has no profiles", config_path)))?;
+    let profile = match profile_name {
+        Some(name) => profiles
+            .iter_mut()
+            .find(|p| p.get("name").and_then(|n| n.as_str()) == Some(name)),
+        None => profiles
+            .iter_mut()
+            .find(|p| p.get("selected").and_then(|s| s.as_bool()) == Some(true)),
+    }
+    .and_then(|profile| profile.as_object_mut())
+    .ok_or_else(|| {
+        MergeError::ProfileNotFound(
+            profile_name
+                .map(|name| format!("'{}'", name))
+                .unwrap_or_else(|| "no profile is selected".to_string()),
+        )
+    })?;
+
+    let rules = profile
+        .entry("complex_modifications")
+        .or_insert_with(|| serde_json::json!({}))
+        .as_object_mut()
+        .ok_or_else(|| MergeError::JsonError("complex_modifications is not an object".to_string()))?
+        .entry("rules")
+        .or_insert_with(|| serde_json::json!([]))
+        .as_array_mut()
+        .ok_or_else(|| {
+            MergeError::JsonError("complex_modifications.rules is not an array".to_string())
+        })?;
+
+    let same_description = |rule: &serde_json::Value| {
+        rule.get("make_karabiner_id").is_none()
+            && generated.rules.iter().any(|new| {
+                rule.get("description").and_then(|d| d.as_str()) == Some(new.description.as_str())
+            })
+    };
+    let generated_rule = |rule: &serde_json::Value| match rule.get("make_karabiner_id") {
+        Some(id) => generated
+            .rules
+            .iter()
+            .any(|new| new.make_karabiner_id.as_deref() == id.as_str()),
+        None => replace_by_description && same_description(rule),
+    };
+    let kept_descriptions = if replace_by_description {
+        Vec::new()
+    } else {
+        rules
+            .iter()
+            .filter(|rule| same_description(rule))
+            .filter_map(|rule| rule.get("description")?.as_str().map(String::from))
+            .collect()
+    };
+    let insert_at = rules.iter().position(generated_rule).unwrap_or(rules.len());
+    let mut new_rules = Vec::new();
+    for rule in &generated.rules {
+        new_rules.push(
+            serde_json::to_value(rule)
+                .map_err(|e| MergeError::JsonError(format!("Failed to serialize rule: {}", e)))?,
+        );
+    }
+    rules.retain(|rule| !generated_rule(rule));
+    rules.splice(insert_at..insert_at, new_rules);
+
+    let json = serde_json::to_string_pretty(&config).map_err(|e| {
+        MergeError::JsonError(format!("Failed to serialize {}: {}", config_path, e))
+    })?;
+    Ok(MergedConfig {
+        json,
+        kept_descriptions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_structures::Rule;
+
+    fn generated_rule(description: &str) -> Rule {
+        Rule {
+            description: description.to_string(),
+            enabled: None,
+            make_karabiner_id: Some(merge_rule_id("src/data/shingeta.rs")),
+            manipulators: Vec::new(),
+        }
+    }
+
+    fn merge(
+        name: &str,
+        karabiner_json: &str,
+        rules: Vec<Rule>,
+        replace_by_description: bool,
+    ) -> (serde_json::Value, Vec<String>) {
+        let path = std::env::temp_dir().join(format!(
+            "make_karabiner_merge_{}_{}.json",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, karabiner_json).unwrap();
+        let generated = File { title: None, rules };
+        let merged = merge_rules_into_karabiner_json(
+            path.to_str().unwrap(),
+            None,
+            &generated,
+            replace_by_description,
+        )
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+        (
+            serde_json::from_str(&merged.json).unwrap(),
+            merged.kept_descriptions,
+        )
+    }
+
+    fn descriptions(merged: &serde_json::Value) -> Vec<&str> {
+        merged["profiles"][0]["complex_modifications"]["rules"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|rule| rule["description"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn unrelated_content_keeps_its_key_order() {
+        let (merged, _) = merge(
+            "order",
+            r#"{
+                "global": {"show_in_menu_bar": false, "check_for_updates_on_startup": true},
+                "profiles": [{
+                    "selected": true,
+                    "virtual_hid_keyboard": {"keyboard_type_v2": "jis"},
+                    "name": "Default",
+                    "complex_modifications": {
+                        "rules": [{"manipulators": [], "description": "mine", "enabled": false}],
+                        "parameters": {"basic.to_if_alone_timeout_milliseconds": 500}
+                    },
+                    "devices": []
+                }]
+            }"#,
+            vec![generated_rule("shingeta")],
+            false,
+        );
+        let keys = |value: &serde_json::Value| -> Vec<String> {
+            value.as_object().unwrap().keys().cloned().collect()
+        };
+        assert_eq!(keys(&merged), ["global", "profiles"]);
+        assert_eq!(
+            keys(&merged["global"]),
+            ["show_in_menu_bar", "check_for_updates_on_startup"]
+        );
+        let profile = &merged["profiles"][0];
+        assert_eq!(
+            keys(profile),
+            [
+                "selected",
+                "virtual_hid_keyboard",
+                "name",
+                "complex_modifications",
+                "devices"
+            ]
+        );
+        assert_eq!(
+            keys(&profile["complex_modifications"]["rules"][0]),
+            ["manipulators", "description", "enabled"]
+        );
+        assert_eq!(descriptions(&merged), ["mine", "shingeta"]);
+    }
+
+    const TAGGED: &str = r#"{"profiles": [{"name": "Default", "selected": true, "complex_modifications": {"rules": [
+        {"description": "before", "manipulators": []},
+        {"description": "old (JIS)", "make_karabiner_id": "src/data/shingeta.rs", "manipulators": []},
+        {"description": "other", "make_karabiner_id": "src/data/layout.rs", "manipulators": []},
+        {"description": "old (ANSI)", "make_karabiner_id": "src/data/shingeta.rs", "manipulators": []},
+        {"description": "new", "manipulators": []}
+    ]}}]}"#;
+
+    #[test]
+    fn generated_rules_are_replaced_by_their_id() {
+        // description が変わっても、前回の変種 (ANSI) が残らない。
+        // 目印のない同名のルールはユーザーのものかもしれないので残し、知らせる
+        let (merged, kept) = merge("id", TAGGED, vec![generated_rule("new")], false);
+        assert_eq!(descriptions(&merged), ["before", "new", "other", "new"]);
+        assert_eq!(kept, ["new"]);
+        let rules = &merged["profiles"][0]["complex_modifications"]["rules"];
+        assert_eq!(rules[1]["make_karabiner_id"], "src/data/shingeta.rs");
+        assert!(rules[3].get("make_karabiner_id").is_none());
+    }
+
+    #[test]
+    fn untagged_rules_are_replaced_only_when_asked() {
+        let (merged, kept) = merge("description", TAGGED, vec![generated_rule("new")], true);
+        assert_eq!(descriptions(&merged), ["before", "new", "other"]);
+        assert!(kept.is_empty());
+    }
+
+    #[test]
+    fn rule_ids_are_normalized_relative_paths() {
+        let base = Path::new("/home/me/layouts");
+        let id = |path: &str| normalized_rule_id(Path::new(path), base);
+        assert_ne!(id("a/keys.rs"), id("b/keys.rs"));
+        assert_eq!(id("a/keys.rs"), "a/keys.rs");
+        assert_eq!(id("./a/../a/keys.rs"), "a/keys.rs");
+        assert_eq!(id("/home/me/layouts/a/keys.rs"), "a/keys.rs");
+        // base の外はそのまま (正規化した絶対パス)
+        assert_eq!(id("/etc/keys.rs"), "/etc/keys.rs");
+    }
+}
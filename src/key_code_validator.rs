@@ -0,0 +1,86 @@
+use crate::diagnostics::Diagnostic;
+use crate::json_structures::{KeyCode, Manipulator};
+use crate::karabiner_config_generator::{generate_manipulator_groups, RuleOptions, RuleVariant};
+use crate::karabiner_key_codes::{is_valid_consumer_key_code, is_valid_pointing_button};
+use crate::mapping_source::Mapping;
+
+fn unknown_key_code_message(name: &str, mapping: &Mapping) -> String {
+    let hint = if is_valid_consumer_key_code(name) {
+        " (it is a consumer_key_code, not a key_code)"
+    } else if is_valid_pointing_button(name) {
+        " (it is a pointing_button, not a key_code)"
+    } else {
+        ""
+    };
+    format!(
+        "Unknown key_code '{}'{} generated from mapping ({:?}, {:?})",
+        name, hint, mapping.from, mapping.to
+    )
+}
+
+fn collect_key_codes(manipulator: &Manipulator) -> Vec<&KeyCode> {
+    let mut key_codes: Vec<&KeyCode> = manipulator.from.key_code.iter().collect();
+    if let Some(keys) = &manipulator.from.simultaneous {
+        key_codes.extend(keys.iter().map(|k| &k.key_code));
+    }
+    for events in [
+        &manipulator.to,
+        &manipulator.to_if_alone,
+        &manipulator.to_if_held_down,
+        &manipulator.to_after_key_up,
+    ] {
+        key_codes.extend(events.iter().filter_map(|event| event.key_code.as_ref()));
+    }
+    if let Some(options) = &manipulator.from.simultaneous_options {
+        key_codes.extend(
+            options
+                .to_after_key_up
+                .iter()
+                .filter_map(|event| event.key_code.as_ref()),
+        );
+    }
+    key_codes
+}
+
+// 各マッピング (レイヤーの見出しを含む) から生成される manipulator を検査し、
+// Karabiner が知らないキー名 (KeyCode::Other として素通しされたもの) を警告として返す
+pub fn validate_generated_key_codes(
+    mappings: &[Mapping],
+    options: &RuleOptions,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for (mapping, manipulators) in generate_manipulator_groups(mappings, options) {
+        let mut reported: Vec<String> = Vec::new();
+        for manipulator in manipulators {
+            for key_code in collect_key_codes(&manipulator) {
+                if let KeyCode::Other(name) = key_code {
+                    if !reported.contains(name) {
+                        diagnostics.push(Diagnostic::warning(
+                            unknown_key_code_message(name, mapping),
+                            mapping.location.clone(),
+                        ));
+                        reported.push(name.clone());
+                    }
+                }
+            }
+        }
+    }
+    diagnostics
+}
+
+// 変種ごとに配列などが変わるため、すべての変種を検査して重複を除いた警告を返す
+pub fn validate_rule_variants(
+    mappings: &[Mapping],
+    options: &RuleOptions,
+    variants: &[RuleVariant],
+) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    for variant in variants {
+        for diagnostic in validate_generated_key_codes(mappings, &variant.apply(options)) {
+            if !diagnostics.contains(&diagnostic) {
+                diagnostics.push(diagnostic);
+            }
+        }
+    }
+    diagnostics
+}
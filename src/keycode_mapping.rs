@@ -1,175 +1,20 @@
-use std::{collections::HashMap, default};
-
-fn get_jis_to_karabiner_map() -> HashMap<&'static str, &'static str> {
-    HashMap::from([
-        ("-", "hyphen"),
-        (",", "comma"),
-        (".", "period"),
-        ("/", "slash"),
-        ("=", "equal_sign"),
-        ("@", "open_bracket"),
-        ("[", "close_bracket"),
-        ("]", "backslash"),
-        (";", "semicolon"),
-        (":", "quote"),
-        ("_", "international1"),
-    ])
-}
+use std::rc::Rc;
 
-pub fn convert_jis_symbol_to_keycode_str(jis_symbol: &str) -> Option<&'static str> {
-    get_jis_to_karabiner_map().get(jis_symbol).copied()
-}
+use unicode_normalization::UnicodeNormalization;
 
-fn get_hiragana_to_romaji_map() -> HashMap<&'static str, &'static str> {
-    HashMap::from([
-        // 清音
-        ("あ", "a"),
-        ("い", "i"),
-        ("う", "u"),
-        ("え", "e"),
-        ("お", "o"),
-        ("か", "ka"),
-        ("き", "ki"),
-        ("く", "ku"),
-        ("け", "ke"),
-        ("こ", "ko"),
-        ("さ", "sa"),
-        ("し", "shi"),
-        ("す", "su"),
-        ("せ", "se"),
-        ("そ", "so"),
-        ("た", "ta"),
-        ("ち", "chi"),
-        ("つ", "tsu"),
-        ("て", "te"),
-        ("と", "to"),
-        ("な", "na"),
-        ("に", "ni"),
-        ("ぬ", "nu"),
-        ("ね", "ne"),
-        ("の", "no"),
-        ("は", "ha"),
-        ("ひ", "hi"),
-        ("ふ", "fu"),
-        ("へ", "he"),
-        ("ほ", "ho"),
-        ("ま", "ma"),
-        ("み", "mi"),
-        ("む", "mu"),
-        ("め", "me"),
-        ("も", "mo"),
-        ("や", "ya"),
-        ("ゆ", "yu"),
-        ("よ", "yo"),
-        ("ら", "ra"),
-        ("り", "ri"),
-        ("る", "ru"),
-        ("れ", "re"),
-        ("ろ", "ro"),
-        ("わ", "wa"),
-        ("ゐ", "wi"),
-        ("ゑ", "we"),
-        ("を", "wo"), // ゐ (wi), ゑ (we) は現代では稀
-        ("ん", "nn"),
-        // 濁音
-        ("が", "ga"),
-        ("ぎ", "gi"),
-        ("ぐ", "gu"),
-        ("げ", "ge"),
-        ("ご", "go"),
-        ("ざ", "za"),
-        ("じ", "ji"),
-        ("ず", "zu"),
-        ("ぜ", "ze"),
-        ("ぞ", "zo"),
-        ("だ", "da"),
-        ("ぢ", "di"),
-        ("づ", "du"),
-        ("で", "de"),
-        ("ど", "do"), // ぢ (di/ji), づ (du/zu)
-        ("ば", "ba"),
-        ("び", "bi"),
-        ("ぶ", "bu"),
-        ("べ", "be"),
-        ("ぼ", "bo"),
-        // 半濁音
-        ("ぱ", "pa"),
-        ("ぴ", "pi"),
-        ("ぷ", "pu"),
-        ("ぺ", "pe"),
-        ("ぽ", "po"),
-        // 拗音 (きゃ行など)
-        ("きゃ", "kya"),
-        ("きゅ", "kyu"),
-        ("きょ", "kyo"),
-        ("しゃ", "sha"),
-        ("しゅ", "shu"),
-        ("しょ", "sho"), // し (shi) ベース
-        ("ちゃ", "cha"),
-        ("ちゅ", "chu"),
-	("ちぇ", "che"),
-        ("ちょ", "cho"), // ち (chi) ベース
-        ("にゃ", "nya"),
-        ("にゅ", "nyu"),
-        ("にょ", "nyo"),
-        ("ひゃ", "hya"),
-        ("ひゅ", "hyu"),
-        ("ひょ", "hyo"),
-        ("みゃ", "mya"),
-        ("みゅ", "myu"),
-        ("みょ", "myo"),
-        ("りゃ", "rya"),
-        ("りゅ", "ryu"),
-        ("りょ", "ryo"),
-        // 拗音 (ぎゃ行など - 濁音の拗音)
-        ("ぎゃ", "gya"),
-        ("ぎゅ", "gyu"),
-        ("ぎょ", "gyo"),
-        ("じゃ", "ja"),
-        ("じゅ", "ju"),
-        ("じょ", "jo"), // じ (ji) ベース
-        ("ぢゃ", "dya"),
-        ("ぢゅ", "dyu"),
-        ("ぢょ", "dyo"), // ぢ (di) ベース (じゃ、じゅ、じょ との区別)
-        ("びゃ", "bya"),
-        ("びゅ", "byu"),
-        ("びょ", "byo"),
-        // 拗音 (ぴゃ行など - 半濁音の拗音)
-        ("ぴゃ", "pya"),
-        ("ぴゅ", "pyu"),
-        ("ぴょ", "pyo"),
-        // 小さい「ぁぃぅぇぉ」 (例: ファ (fa) のような外来語表記用)
-        // これらは単独でローマ字になるというより、前の文字と組み合わさることが多い。
-        // もし単独で処理する必要がある場合 (例: "ぁ" -> "xa" など) は追加する。
-        ("ふぁ", "fa"),
-        ("ふぃ", "fi"),
-        ("ふぇ", "fe"),
-        ("ふぉ", "fo"),
-        ("うぃ", "uxi"),
-        ("うぇ", "uxe"),
-        ("うぉ", "uxo"),
-        ("う゛", "vu"),
-	("てぃ", "texi"),
-	("でぃ", "dexi"),
-	("しぇ", "sye"),
-	("じぇ", "je"),
-			
-        // 通常は IME が "fa" -> "ふぁ" のように処理する。
-        // 今回はキー入力シーケンスの生成が目的なので、単独の小さい仮名は含めない方向で。
-        ("っ", "xtsu"),
-        // 長音記号
-        ("ー", "-"), // キーボードのハイフンと同じになるが、文脈で判断
-        ("、", ","),
-        ("。", "."),
-        ("!", "!"),
-	("？", "?"),
-        // その他記号 (句読点など) - これらは通常 process_key_symbol の別の箇所で処理されるか、
-        // もしくはキーコードとして直接定義されるべきものが多い。
-        // 必要であれば、特定の記号のローマ字読みのようなものを追加することも可能。
-        // ("。", "."), ("、", ","), ("「", "["), ("」", "]") などは、
-        // get_jis_to_karabiner_map や特別ルールで処理する方が適切。
-    ])
+use crate::host_layout::HostLayout;
+use crate::json_structures::{KeyCode, Modifier, ToEvent};
+use crate::kana_input::{jis_kana_keys, KanaInput};
+use crate::romaji_scheme::RomajiScheme;
+
+// 出力先の Mac の配列と、仮名の入力方式 (ローマ字入力ならその綴り)
+#[derive(Debug, Default, Clone)]
+pub struct TypingEnvironment {
+    pub layout: HostLayout,
+    pub kana_input: KanaInput,
+    pub romaji: Rc<RomajiScheme>,
 }
+
 #[derive(Debug, Default, Clone, PartialEq)]
 pub enum FromEventType {
     #[default]
@@ -179,106 +24,387 @@ pub enum FromEventType {
 #[derive(Debug, Default, Clone)]
 pub struct ParsedFromEvent {
     pub event_type: FromEventType,
-    pub key_code: Option<String>,
-    pub modifiers: Vec<String>,
-    pub simultaneous_keys: Option<Vec<String>>,
+    pub key_code: Option<KeyCode>,
+    pub modifiers: Vec<Modifier>,
+    pub optional_modifiers: Vec<Modifier>,
+    pub simultaneous_keys: Option<Vec<KeyCode>>,
+    // `simul(k q; order=strict)` の ; 以降
+    pub simultaneous_options: Option<String>,
 }
 
 #[derive(Debug, Default, Clone)]
-pub struct TransformedToKey {
-    pub key_code: String,
-    pub mandatory_modifiers: Vec<String>,
+pub struct ParsedToEvent {
+    pub to: Option<String>,
+    pub to_if_alone: Option<String>,
+    pub to_if_held_down: Option<String>,
 }
 
-pub fn transform_string_for_to_event(symbol_str: &str) -> TransformedToKey {
-    let mut current_processing_str = symbol_str.to_string();
-    let mut modifiers = Vec::new();
-    let final_key_code: String;
+#[derive(Debug, Default, Clone)]
+struct TransformedToKey {
+    // 1 つのキー、またはローマ字のように順に打つキーの列
+    pub key_codes: Vec<KeyCode>,
+    pub mandatory_modifiers: Vec<Modifier>,
+}
 
-    if let Some(romaji) = get_hiragana_to_romaji_map().get(symbol_str) {
-        current_processing_str = romaji.to_string();
+// Karabiner のキー名であればそのキー 1 つ、英小文字だけの列 (ローマ字) なら 1 文字ずつのキー入力にする
+fn key_codes_for_str(s: &str) -> Vec<KeyCode> {
+    let key_code = KeyCode::from_name(s);
+    if !key_code.is_known() && s.len() > 1 && s.chars().all(|c| c.is_ascii_lowercase()) {
+        return s
+            .chars()
+            .map(|c| KeyCode::from_name(&c.to_string()))
+            .collect();
     }
+    vec![key_code]
+}
 
-    match current_processing_str.as_str() {
-        "=" => {
-            final_key_code = convert_jis_symbol_to_keycode_str("-")
-                .unwrap_or("-")
-                .to_string();
-            modifiers.push("left_shift".to_string());
-            return TransformedToKey {
-                key_code: final_key_code,
-                mandatory_modifiers: modifiers,
-            };
+impl TransformedToKey {
+    fn into_to_events(self) -> Vec<ToEvent> {
+        let modifiers = if self.mandatory_modifiers.is_empty() {
+            None
+        } else {
+            Some(self.mandatory_modifiers)
+        };
+        self.key_codes
+            .into_iter()
+            .map(|key_code| ToEvent {
+                key_code: Some(key_code),
+                modifiers: modifiers.clone(),
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+// 出力の種類を明示する接頭辞。接頭辞がなければ従来どおり文字列から推測する。
+//   text:かな            仮名や英字を env の入力方式 (ローマ字入力/かな入力) のキー列として打つ
+//   key:escape           Karabiner のキー名 1 つ
+//   combo:cmd+shift+4    修飾キー付きのキー 1 つ
+// 記号は env の配列で入力するキーに、仮名はローマ字の綴りかかな入力のキーに変換する。
+pub fn transform_string_for_to_event(symbol_str: &str, env: &TypingEnvironment) -> Vec<ToEvent> {
+    if let Some(text) = symbol_str.strip_prefix("text:") {
+        return text_to_events(strip_quotes(text), env);
+    }
+    if let Some(key) = symbol_str.strip_prefix("key:") {
+        return vec![ToEvent {
+            key_code: Some(KeyCode::from_name(strip_quotes(key))),
+            modifiers: None,
+            ..Default::default()
+        }];
+    }
+    if let Some(combo) = symbol_str.strip_prefix("combo:") {
+        let (modifiers, key_code) = parse_combo(combo, env)
+            .unwrap_or_else(|_| (Vec::new(), KeyCode::Other(combo.to_string())));
+        return vec![ToEvent {
+            key_code: Some(key_code),
+            modifiers: if modifiers.is_empty() {
+                None
+            } else {
+                Some(modifiers)
+            },
+            ..Default::default()
+        }];
+    }
+    if let Some((specs, key)) = split_modifier_notation(symbol_str) {
+        let modifiers = specs
+            .iter()
+            .map(|spec| modifier_for_to_event(spec.modifier))
+            .collect();
+        let (modifiers, key_code) = key_with_modifiers(key, modifiers, env);
+        return vec![ToEvent {
+            key_code: Some(key_code),
+            modifiers: Some(modifiers),
+            ..Default::default()
+        }];
+    }
+    if let Some(events) = kana_key_events(symbol_str, env) {
+        return events;
+    }
+    transform_symbol(symbol_str, env).into_to_events()
+}
+
+// かな入力のとき、仮名だけの文字列を仮名ごとのキー列にする。キーのない仮名はそのままキー名として残し、検証で報告させる。
+fn kana_key_events(text: &str, env: &TypingEnvironment) -> Option<Vec<ToEvent>> {
+    if env.kana_input != KanaInput::JisKana {
+        return None;
+    }
+    let keys = jis_kana_keys(&normalize_kana(text))?;
+    Some(
+        keys.into_iter()
+            .map(|(kana, symbol_key)| match symbol_key {
+                Some(symbol_key) => ToEvent {
+                    key_code: Some(KeyCode::from_name(symbol_key.key_code)),
+                    modifiers: if symbol_key.modifiers.is_empty() {
+                        None
+                    } else {
+                        Some(symbol_key.modifiers.to_vec())
+                    },
+                    ..Default::default()
+                },
+                None => ToEvent {
+                    key_code: Some(KeyCode::from_name(&kana.to_string())),
+                    ..Default::default()
+                },
+            })
+            .collect(),
+    )
+}
+
+fn strip_quotes(s: &str) -> &str {
+    let s = s.trim();
+    s.strip_prefix('"')
+        .and_then(|inner| inner.strip_suffix('"'))
+        .unwrap_or(s)
+}
+
+// 修飾キーの記法。from と to のどちらにも書ける。
+//   emacs 風:  C-a (control)  M-a (option)  S-a (shift)  s-a (command)、C-M-a のように重ねられる
+//   + 区切り:  cmd+shift+z、左右を区別する場合は lcmd+ / right_shift+ など
+// 左右を指定しない修飾キーは、from ではどちらの側でも一致し、to では左側のキーとして出力する。
+// from では先頭に ? を付けた修飾キー (?S-a, cmd+?shift+z) は optional になる。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModifierSpec {
+    pub modifier: Modifier,
+    pub optional: bool,
+}
+
+fn modifier_from_emacs_prefix(prefix: &str) -> Option<Modifier> {
+    match prefix {
+        "C" => Some(Modifier::Control),
+        "M" => Some(Modifier::Option),
+        "S" => Some(Modifier::Shift),
+        "s" => Some(Modifier::Command),
+        _ => None,
+    }
+}
+
+fn modifier_from_plus_name(name: &str) -> Option<Modifier> {
+    match name {
+        "cmd" | "command" => Some(Modifier::Command),
+        "ctrl" | "control" => Some(Modifier::Control),
+        "opt" | "option" | "alt" | "meta" => Some(Modifier::Option),
+        "shift" => Some(Modifier::Shift),
+        "lcmd" | "left_cmd" => Some(Modifier::LeftCommand),
+        "rcmd" | "right_cmd" => Some(Modifier::RightCommand),
+        "lctrl" | "left_ctrl" => Some(Modifier::LeftControl),
+        "rctrl" | "right_ctrl" => Some(Modifier::RightControl),
+        "lopt" | "left_opt" | "lalt" | "left_alt" => Some(Modifier::LeftOption),
+        "ropt" | "right_opt" | "ralt" | "right_alt" => Some(Modifier::RightOption),
+        "lshift" => Some(Modifier::LeftShift),
+        "rshift" => Some(Modifier::RightShift),
+        _ => Modifier::from_name(name),
+    }
+}
+
+fn parse_modifier_token(
+    token: &str,
+    lookup: fn(&str) -> Option<Modifier>,
+) -> Option<ModifierSpec> {
+    let (optional, name) = match token.strip_prefix('?') {
+        Some(name) => (true, name),
+        None => (false, token),
+    };
+    lookup(name).map(|modifier| ModifierSpec { modifier, optional })
+}
+
+// to では左右の区別がない修飾キーを左側のキーとして出力する
+pub fn modifier_for_to_event(modifier: Modifier) -> Modifier {
+    match modifier {
+        Modifier::Command => Modifier::LeftCommand,
+        Modifier::Control => Modifier::LeftControl,
+        Modifier::Option => Modifier::LeftOption,
+        Modifier::Shift => Modifier::LeftShift,
+        other => other,
+    }
+}
+
+// 修飾キーの記法で書かれていれば、修飾キーと残りのキー部分に分ける
+pub fn split_modifier_notation(input_str: &str) -> Option<(Vec<ModifierSpec>, &str)> {
+    let mut specs = Vec::new();
+    let mut rest = input_str;
+    while let Some((head, tail)) = rest.split_once('-') {
+        match parse_modifier_token(head, modifier_from_emacs_prefix) {
+            Some(spec) if !tail.is_empty() => {
+                specs.push(spec);
+                rest = tail;
+            }
+            _ => break,
         }
-        "'" => {
-            final_key_code = convert_jis_symbol_to_keycode_str("7")
-                .unwrap_or("7")
-                .to_string();
-            modifiers.push("left_shift".to_string());
-            return TransformedToKey {
-                key_code: final_key_code,
-                mandatory_modifiers: modifiers,
-            };
+    }
+    if !specs.is_empty() {
+        return Some((specs, rest));
+    }
+
+    // "cmd++" のように + キーそのものを最後に書ける
+    let (body, key) = match input_str.strip_suffix("++") {
+        Some(body) => (body, "+"),
+        None => input_str.rsplit_once('+')?,
+    };
+    if body.is_empty() || key.is_empty() {
+        return None;
+    }
+    let specs = body
+        .split('+')
+        .map(|token| parse_modifier_token(token.trim(), modifier_from_plus_name))
+        .collect::<Option<Vec<_>>>()?;
+    Some((specs, key))
+}
+
+// キー部分を変換し、記号の入力に必要な修飾キー (JIS の ! なら Shift) と合わせる
+fn key_with_modifiers(
+    key: &str,
+    mut modifiers: Vec<Modifier>,
+    env: &TypingEnvironment,
+) -> (Vec<Modifier>, KeyCode) {
+    let transformed = transform_symbol(key, env);
+    let key_code = match transformed.key_codes.as_slice() {
+        [single] => single.clone(),
+        _ => KeyCode::from_name(key),
+    };
+    for modifier in transformed.mandatory_modifiers {
+        if !modifiers.contains(&modifier) {
+            modifiers.push(modifier);
         }
-        "!" => {
-            final_key_code = convert_jis_symbol_to_keycode_str("1")
-                .unwrap_or("1")
-                .to_string();
-            modifiers.push("left_shift".to_string());
-            return TransformedToKey {
-                key_code: final_key_code,
-                mandatory_modifiers: modifiers,
-            };
+    }
+    (modifiers, key_code)
+}
+
+// "cmd+shift+4" や "C-S-4" を修飾キーとキーに分ける。最後の要素がキーになる。
+pub fn parse_combo(combo: &str, env: &TypingEnvironment) -> Result<(Vec<Modifier>, KeyCode), String> {
+    let combo = strip_quotes(combo);
+    if let Some((specs, key)) = split_modifier_notation(combo) {
+        let modifiers = specs
+            .iter()
+            .map(|spec| modifier_for_to_event(spec.modifier))
+            .collect();
+        return Ok(key_with_modifiers(key, modifiers, env));
+    }
+
+    // 記法として解釈できなかった場合に、どこが誤っているかを報告する
+    let parts: Vec<&str> = combo.split('+').map(str::trim).collect();
+    let (key, modifier_names) = match parts.split_last() {
+        Some((key, modifier_names)) if !key.is_empty() => (key, modifier_names),
+        _ => return Err(format!("'combo:{}' does not end with a key", combo)),
+    };
+    if let Some(name) = modifier_names
+        .iter()
+        .find(|name| modifier_from_plus_name(name).is_none())
+    {
+        return Err(format!("Unknown modifier '{}' in 'combo:{}'", name, combo));
+    }
+    Ok(key_with_modifiers(key, Vec::new(), env))
+}
+
+// 濁点・半濁点を前の仮名と合成した形 (NFC) にそろえる。
+// 単独の「゛」「゜」(U+309B/U+309C) も結合文字として扱うため、「う゛」や「か゛」も ゔ・が になる。
+pub fn normalize_kana(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\u{309B}' => '\u{3099}',
+            '\u{309C}' => '\u{309A}',
+            other => other,
+        })
+        .nfc()
+        .collect()
+}
+
+// 文字列を先頭から仮名単位 (拗音など 2 文字の組を優先) に区切ってキー列にする
+fn text_to_events(text: &str, env: &TypingEnvironment) -> Vec<ToEvent> {
+    let chars: Vec<char> = normalize_kana(text).chars().collect();
+    let mut events = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if let Some(kana_events) = kana_key_events(&chars[i].to_string(), env) {
+            events.extend(kana_events);
+            i += 1;
+            continue;
+        }
+        if i + 1 < chars.len() {
+            let pair: String = chars[i..i + 2].iter().collect();
+            if env.romaji.romaji(&pair).is_some() {
+                events.extend(transform_symbol(&pair, env).into_to_events());
+                i += 2;
+                continue;
+            }
         }
-	        
-        "?" => {
-            final_key_code = convert_jis_symbol_to_keycode_str("/")
-                .unwrap_or("/")
-                .to_string();
-            modifiers.push("left_shift".to_string());
+        events.extend(transform_symbol(&chars[i].to_string(), env).into_to_events());
+        i += 1;
+    }
+    events
+}
+
+fn transform_symbol(symbol_str: &str, env: &TypingEnvironment) -> TransformedToKey {
+    let symbol_str = &normalize_kana(symbol_str);
+    let mut current_processing_str = symbol_str.to_string();
+
+    if let Some(romaji) = env.romaji.romaji(symbol_str) {
+        current_processing_str = romaji.to_string();
+    }
+
+    if let Some(symbol_key) = env.layout.symbol_key(&current_processing_str) {
+        return TransformedToKey {
+            key_codes: key_codes_for_str(symbol_key.key_code),
+            mandatory_modifiers: symbol_key.modifiers.to_vec(),
+        };
+    }
+    // 英大文字は Shift を押しながら小文字のキーを打つ
+    let mut chars = current_processing_str.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if c.is_ascii_uppercase() {
             return TransformedToKey {
-                key_code: final_key_code,
-                mandatory_modifiers: modifiers,
+                key_codes: vec![KeyCode::from_name(&c.to_ascii_lowercase().to_string())],
+                mandatory_modifiers: vec![Modifier::LeftShift],
             };
         }
-	
-
-        _ => {}
     }
-    if let Some(kc_str) = convert_jis_symbol_to_keycode_str(&current_processing_str) {
-        final_key_code = kc_str.to_string();
-    } else if current_processing_str.len() == 1
+    let final_key_code = if current_processing_str.len() == 1
         && current_processing_str
             .chars()
             .next()
             .is_some_and(|c| c.is_alphabetic())
     {
-        final_key_code = current_processing_str.to_lowercase();
+        current_processing_str.to_lowercase()
     } else {
-        final_key_code = current_processing_str;
-    }
+        current_processing_str
+    };
     TransformedToKey {
-        key_code: final_key_code,
-        mandatory_modifiers: modifiers,
+        key_codes: key_codes_for_str(&final_key_code),
+        mandatory_modifiers: Vec::new(),
+    }
+}
+
+// `simul(k q; order=strict)` の括弧の中をキーとオプションに分ける。
+// ; はキー (`simul(d ;)`) にもなるため、最後の ; より後に key=value がある場合だけ区切りとみなす。
+pub fn split_simultaneous_options(inner: &str) -> (&str, Option<&str>) {
+    match inner.rsplit_once(';') {
+        Some((keys, options)) if options.contains('=') => (keys, Some(options.trim())),
+        _ => (inner, None),
     }
 }
 
-pub fn parse_from_input_string(input_str: &str) -> ParsedFromEvent {
+pub fn parse_from_input_string(input_str: &str, env: &TypingEnvironment) -> ParsedFromEvent {
+    // from と同時押しのキーは手元の JIS キーボードの物理キーなので、出力先の配列 (env.layout) では解釈しない
+    let env = &TypingEnvironment {
+        layout: HostLayout::Jis,
+        ..env.clone()
+    };
     if input_str.starts_with("simul(") && input_str.ends_with(")") {
-        if let Some(keys_part) = input_str.get(6..input_str.len() - 1) {
-            let keys: Vec<String> = keys_part
+        if let Some(inner) = input_str.get(6..input_str.len() - 1) {
+            let (keys_part, simultaneous_options) = split_simultaneous_options(inner);
+            let keys: Vec<KeyCode> = keys_part
                 .split_whitespace()
                 .map(|s| s.trim().to_string())
                 .filter(|s| !s.is_empty())
                 .map(|s_val| {
-                    if let Some(kc) = convert_jis_symbol_to_keycode_str(&s_val) {
-                        kc.to_string()
+                    // 同時押しには修飾キーを付けられないため、記号はキーだけを使う
+                    if let Some(symbol_key) = env.layout.symbol_key(&s_val) {
+                        KeyCode::from_name(symbol_key.key_code)
                     } else if s_val.len() == 1
                         && s_val.chars().next().unwrap().is_ascii_alphabetic()
                     {
-                        s_val.to_lowercase()
+                        KeyCode::from_name(&s_val.to_lowercase())
                     } else {
-                        s_val
+                        KeyCode::from_name(&s_val)
                     }
                 })
                 .collect();
@@ -287,18 +413,170 @@ pub fn parse_from_input_string(input_str: &str) -> ParsedFromEvent {
                 return ParsedFromEvent {
                     event_type: FromEventType::Simultaneous,
                     simultaneous_keys: Some(keys),
+                    simultaneous_options: simultaneous_options.map(String::from),
                     key_code: None,
                     modifiers: Vec::new(),
+                    optional_modifiers: Vec::new(),
                 };
             }
         }
     }
-    let single_key_transformed = transform_string_for_to_event(input_str);
+    if let Some((specs, key)) = split_modifier_notation(input_str) {
+        let mandatory = specs
+            .iter()
+            .filter(|spec| !spec.optional)
+            .map(|spec| spec.modifier)
+            .collect();
+        let (modifiers, key_code) = key_with_modifiers(key, mandatory, env);
+        return ParsedFromEvent {
+            event_type: FromEventType::SingleKey,
+            key_code: Some(key_code),
+            modifiers,
+            optional_modifiers: specs
+                .iter()
+                .filter(|spec| spec.optional)
+                .map(|spec| spec.modifier)
+                .collect(),
+            simultaneous_keys: None,
+            simultaneous_options: None,
+        };
+    }
+
+    let single_key_transformed = transform_symbol(input_str, env);
+    // from 側はキー 1 つだけなので、キー列に展開された場合は元の名前のまま扱う
+    let key_code = match single_key_transformed.key_codes.as_slice() {
+        [single] => single.clone(),
+        _ => KeyCode::Other(
+            single_key_transformed
+                .key_codes
+                .iter()
+                .map(KeyCode::name)
+                .collect(),
+        ),
+    };
 
     ParsedFromEvent {
         event_type: FromEventType::SingleKey,
-        key_code: Some(single_key_transformed.key_code),
+        key_code: Some(key_code),
         modifiers: single_key_transformed.mandatory_modifiers,
+        optional_modifiers: Vec::new(),
         simultaneous_keys: None,
+        simultaneous_options: None,
+    }
+}
+
+// 空白で区切る。ただし text:"a b" のような "..." の中の空白では区切らない
+fn split_outside_quotes(input: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+    for (index, c) in input.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        if c.is_whitespace() && !quoted {
+            if let Some(token_start) = start.take() {
+                tokens.push(&input[token_start..index]);
+            }
+        } else if start.is_none() {
+            start = Some(index);
+        }
+    }
+    if let Some(token_start) = start {
+        tokens.push(&input[token_start..]);
+    }
+    tokens
+}
+
+// "left_control tap(japanese_eisuu)" のように、押下中の出力に加えて
+// 単独で押した時 (tap) と長押しした時 (hold) の出力を指定できる。
+pub fn parse_to_input_string(input_str: &str) -> ParsedToEvent {
+    if !input_str.contains("tap(") && !input_str.contains("hold(") {
+        return ParsedToEvent {
+            to: Some(input_str.to_string()),
+            ..Default::default()
+        };
+    }
+
+    let mut parsed = ParsedToEvent::default();
+    for token in split_outside_quotes(input_str) {
+        if let Some(inner) = token.strip_prefix("tap(").and_then(|t| t.strip_suffix(")")) {
+            parsed.to_if_alone = Some(inner.to_string());
+        } else if let Some(inner) = token
+            .strip_prefix("hold(")
+            .and_then(|t| t.strip_suffix(")"))
+        {
+            parsed.to_if_held_down = Some(inner.to_string());
+        } else {
+            parsed.to = Some(token.to_string());
+        }
+    }
+    parsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_text_with_spaces_is_kept_next_to_tap() {
+        let parsed = parse_to_input_string(r#"text:"a b" tap(escape)"#);
+        assert_eq!(parsed.to.as_deref(), Some(r#"text:"a b""#));
+        assert_eq!(parsed.to_if_alone.as_deref(), Some("escape"));
+        assert_eq!(parsed.to_if_held_down, None);
+    }
+
+    #[test]
+    fn quoted_text_with_spaces_inside_tap_and_hold() {
+        let parsed = parse_to_input_string(r#"left_shift tap(text:"x y") hold(text:"p q")"#);
+        assert_eq!(parsed.to.as_deref(), Some("left_shift"));
+        assert_eq!(parsed.to_if_alone.as_deref(), Some(r#"text:"x y""#));
+        assert_eq!(parsed.to_if_held_down.as_deref(), Some(r#"text:"p q""#));
+    }
+
+    #[test]
+    fn quoted_text_with_spaces_types_the_space() {
+        let parsed = parse_to_input_string(r#"text:"a b" tap(escape)"#);
+        let events =
+            transform_string_for_to_event(&parsed.to.unwrap(), &TypingEnvironment::default());
+        let names: Vec<&str> = events
+            .iter()
+            .filter_map(|event| event.key_code.as_ref().map(KeyCode::name))
+            .collect();
+        assert_eq!(names, ["a", "spacebar", "b"]);
+    }
+
+    #[test]
+    fn unclosed_quote_keeps_the_rest_in_one_token() {
+        let input = r#"text:"a b tap(escape)"#;
+        assert_eq!(split_outside_quotes(input), [input]);
+        let parsed = parse_to_input_string(input);
+        assert_eq!(parsed.to.as_deref(), Some(input));
+        assert_eq!(parsed.to_if_alone, None);
+    }
+
+    #[test]
+    fn unknown_modifiers_are_not_modifier_notation() {
+        assert!(split_modifier_notation("hyper+a").is_none());
+        assert!(split_modifier_notation("X-a").is_none());
+        assert!(split_modifier_notation("cmd+").is_none());
+        let (specs, key) = split_modifier_notation("cmd+?shift+a").unwrap();
+        assert_eq!(key, "a");
+        assert_eq!(specs.len(), 2);
+        assert!(!specs[0].optional && specs[1].optional);
+    }
+
+    #[test]
+    fn combo_errors_name_the_bad_part() {
+        let env = TypingEnvironment::default();
+        assert_eq!(
+            parse_combo("cmd+hyper+a", &env).unwrap_err(),
+            "Unknown modifier 'hyper' in 'combo:cmd+hyper+a'"
+        );
+        assert_eq!(
+            parse_combo("cmd+", &env).unwrap_err(),
+            "'combo:cmd+' does not end with a key"
+        );
+        assert!(parse_combo("cmd+shift+4", &env).is_ok());
     }
 }
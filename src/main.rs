@@ -1,39 +1,371 @@
 use std::env;
 use std::fs;
 use std::process;
+use std::rc::Rc;
 
-#[macro_use]
 extern crate serde;
 extern crate serde_json;
 
+mod composition_analyzer;
+mod device_presets;
+mod diagnostics;
+mod event_simulator;
+mod host_layout;
+mod ime_table;
 mod json_structures;
+mod kana_input;
 mod karabiner_config_generator;
+mod karabiner_key_codes;
+mod karabiner_profile_merger;
+mod key_code_validator;
 mod keycode_mapping;
+mod manifest;
+mod mapping_sections;
+mod mapping_source;
+mod romaji_scheme;
 mod rust_mappings_parser;
 
-use json_structures::{ConditionVariant, File as KarabinerFile, InputSourceDetail};
-use karabiner_config_generator::generate_karabiner_config;
-use rust_mappings_parser::parse_mappings_from_rust_file;
+use composition_analyzer::analyze_composition;
+use device_presets::{load_device_presets, DevicePresets};
+use event_simulator::{
+    parse_event_script, simulate, tune_simultaneous_threshold, InputEvent, SimulatorEnvironment,
+};
+use host_layout::HostLayout;
+use ime_table::{assign_short_codes, ImeTableFormat};
+use json_structures::{File as KarabinerFile, Parameters};
+use kana_input::KanaInput;
+use karabiner_config_generator::{
+    combine_variants, generate_karabiner_config, generate_karabiner_rule, host_layout_variants,
+    rule_conditions, ApplicationFilter, RuleOptions, ShiftVariantPolicy, SimultaneousSettings,
+};
+use karabiner_profile_merger::{merge_rule_id, merge_rules_into_karabiner_json};
+use key_code_validator::validate_rule_variants;
+use keycode_mapping::TypingEnvironment;
+use manifest::{build_from_manifest, stale_manifest_outputs};
+use mapping_source::load_mappings;
+use romaji_scheme::{RomajiPreset, RomajiScheme};
+
+fn parse_milliseconds(flag: &str, value: &str) -> u64 {
+    match value.parse::<u64>() {
+        Ok(ms) => ms,
+        Err(_) => {
+            eprintln!("Error: {} requires a number of milliseconds", flag);
+            process::exit(1);
+        }
+    }
+}
+
+// --input ごとに 1 つのルールを生成する。--description、--disabled、--rule-id は直前の --input に作用する。
+struct RuleSpec {
+    input_path: String,
+    description: Option<String>,
+    enabled: Option<bool>,
+    // --merge-into で karabiner.json のルールを見分ける目印 (省略時は入力ファイルのパス)
+    rule_id: Option<String>,
+}
+
+// analyze <mappings> [--romaji-scheme <name>] [--romaji-table <tsv>]
+// 出力を続けて打った時に IME の中で別の仮名に変わる組を報告する。見つかれば終了コード 1。
+fn analyze_command(args: &[String]) {
+    let mut input_path: Option<String> = None;
+    let mut romaji_preset = RomajiPreset::default();
+    let mut romaji_table_path: Option<String> = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--romaji-scheme" => {
+                match args.get(i + 1).and_then(|name| RomajiPreset::from_name(name)) {
+                    Some(preset) => romaji_preset = preset,
+                    None => {
+                        eprintln!(
+                            "Error: --romaji-scheme requires one of hepburn, kunrei, aquaskk, kotoeri or google"
+                        );
+                        process::exit(1);
+                    }
+                }
+                i += 1;
+            }
+            "--romaji-table" => {
+                if i + 1 < args.len() {
+                    romaji_table_path = Some(args[i + 1].clone());
+                    i += 1;
+                } else {
+                    eprintln!("Error: --romaji-table requires a path to a TSV file");
+                    process::exit(1);
+                }
+            }
+            path if !path.starts_with("--") => input_path = Some(path.to_string()),
+            _ => {}
+        }
+        i += 1;
+    }
+    let Some(input_path) = input_path else {
+        eprintln!("Error: analyze requires a mappings file");
+        process::exit(1);
+    };
+
+    let mut romaji = RomajiScheme::preset(romaji_preset);
+    if let Some(ref path) = romaji_table_path {
+        if let Err(e) = romaji.merge_google_tsv(path) {
+            eprintln!("Error loading romaji table '{}': {}", path, e);
+            process::exit(1);
+        }
+    }
+    let mappings = match load_mappings(&input_path) {
+        Ok(mappings) => mappings,
+        Err(e) => {
+            eprintln!("Error parsing mappings from '{}': {}", input_path, e);
+            process::exit(1);
+        }
+    };
+
+    let diagnostics = analyze_composition(&mappings, &romaji);
+    for diagnostic in &diagnostics {
+        eprintln!("{}\n", diagnostic.render());
+    }
+    if diagnostics.is_empty() {
+        println!("No composition conflicts in {}", input_path);
+    } else {
+        eprintln!(
+            "{} composition conflict(s) in {}",
+            diagnostics.len(),
+            input_path
+        );
+        process::exit(1);
+    }
+}
+
+// simulate / tune-threshold に共通の、条件の判定に使う Mac の状態を指定するオプション。
+// --bundle-identifier <id> / --input-source-id <id> / --keyboard-type <type> を取り出し、残りの引数を返す。
+fn split_environment_args(args: &[String]) -> (SimulatorEnvironment, Vec<String>) {
+    let mut environment = SimulatorEnvironment::default();
+    let mut rest = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            flag @ ("--bundle-identifier" | "--input-source-id" | "--keyboard-type") => {
+                let Some(value) = args.get(i + 1) else {
+                    eprintln!("Error: {} requires a value", flag);
+                    process::exit(1);
+                };
+                let target = match flag {
+                    "--bundle-identifier" => &mut environment.bundle_identifier,
+                    "--input-source-id" => &mut environment.input_source_id,
+                    _ => &mut environment.keyboard_type,
+                };
+                *target = Some(value.clone());
+                i += 1;
+            }
+            other => rest.push(other.to_string()),
+        }
+        i += 1;
+    }
+    (environment, rest)
+}
+
+// simulate <rules.json> <events.txt> [--bundle-identifier <id>] [--input-source-id <id>] [--keyboard-type <type>]
+// 生成したルールに台本のキー入力を与え、Karabiner が出力するキーを 1 行ずつ表示する。
+fn simulate_command(args: &[String]) {
+    let (environment, args) = split_environment_args(args);
+    let paths: Vec<&String> = args.iter().filter(|a| !a.starts_with("--")).collect();
+    let [rules_path, events_path] = paths.as_slice() else {
+        eprintln!("Error: simulate requires a rules JSON file and an events file");
+        process::exit(1);
+    };
+
+    let (file, events) = read_simulation_inputs(rules_path, events_path);
+
+    let simulation = match simulate(&file, &environment, &events) {
+        Ok(simulation) => simulation,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+    for event in simulation.events {
+        println!("{}", event);
+    }
+}
+
+fn read_simulation_inputs(rules_path: &str, events_path: &str) -> (KarabinerFile, Vec<InputEvent>) {
+    let file: KarabinerFile = match fs::read_to_string(rules_path)
+        .map_err(|e| e.to_string())
+        .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()))
+    {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Error reading rules from '{}': {}", rules_path, e);
+            process::exit(1);
+        }
+    };
+    let events = match fs::read_to_string(events_path)
+        .map_err(|e| e.to_string())
+        .and_then(|content| parse_event_script(&content))
+    {
+        Ok(events) => events,
+        Err(e) => {
+            eprintln!("Error reading events from '{}': {}", events_path, e);
+            process::exit(1);
+        }
+    };
+    (file, events)
+}
+
+// tune-threshold <rules.json> <log.txt> [--from <ms>] [--to <ms>] [--step <ms>]
+//                [--bundle-identifier <id>] [--input-source-id <id>] [--keyboard-type <type>]
+// 記録したキー入力を閾値ごとに流し、同時押しとして判定された数と分かれた数を表にする。
+// input_source_if などの条件付きのルールは、条件に合う環境を指定しないと動かない。
+fn tune_threshold_command(args: &[String]) {
+    let (environment, args) = split_environment_args(args);
+    let mut paths: Vec<String> = Vec::new();
+    let (mut from, mut to, mut step) = (20, 150, 10);
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            flag @ ("--from" | "--to" | "--step") => {
+                let Some(value) = args.get(i + 1) else {
+                    eprintln!("Error: {} requires a value", flag);
+                    process::exit(1);
+                };
+                let target = match flag {
+                    "--from" => &mut from,
+                    "--to" => &mut to,
+                    _ => &mut step,
+                };
+                *target = parse_milliseconds(flag, value);
+                i += 1;
+            }
+            path if !path.starts_with("--") => paths.push(path.to_string()),
+            _ => {}
+        }
+        i += 1;
+    }
+    let [rules_path, log_path] = paths.as_slice() else {
+        eprintln!("Error: tune-threshold requires a rules JSON file and a key event log");
+        process::exit(1);
+    };
+    if step == 0 || from > to {
+        eprintln!("Error: --step must be positive and --from must not exceed --to");
+        process::exit(1);
+    }
+
+    let (mut file, events) = read_simulation_inputs(rules_path, log_path);
+    let thresholds = (from..=to).step_by(step as usize);
+    let results = match tune_simultaneous_threshold(&mut file, &environment, &events, thresholds) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+    if results
+        .iter()
+        .all(|(_, chords)| chords.detected == 0 && chords.split == 0)
+    {
+        eprintln!(
+            "Warning: no chord in the log matched a rule. Rules with conditions need \
+             --input-source-id, --bundle-identifier or --keyboard-type to match."
+        );
+    }
+    println!("threshold_ms\tdetected\tsplit");
+    for (threshold, chords) in results {
+        println!("{}\t{}\t{}", threshold, chords.detected, chords.split);
+    }
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    let mut input_rust_file_path: Option<String> = None;
+    if args.get(1).map(String::as_str) == Some("tune-threshold") {
+        tune_threshold_command(&args[2..]);
+        return;
+    }
+
+    if args.get(1).map(String::as_str) == Some("simulate") {
+        simulate_command(&args[2..]);
+        return;
+    }
+
+    if args.get(1).map(String::as_str) == Some("analyze") {
+        analyze_command(&args[2..]);
+        return;
+    }
+
+    if args.get(1).map(String::as_str) == Some("build") {
+        let build_strict = args[2..].iter().any(|a| a == "--strict");
+        let manifest_path = args[2..]
+            .iter()
+            .find(|a| !a.starts_with("--"))
+            .map(String::as_str)
+            .unwrap_or("manifest.json");
+        // --check はファイルを書き換えず、コミットされた出力が生成結果と一致するかだけを調べる
+        if args[2..].iter().any(|a| a == "--check") {
+            match stale_manifest_outputs(manifest_path, build_strict) {
+                Ok(stale) if stale.is_empty() => println!("All targets are up to date"),
+                Ok(stale) => {
+                    for path in &stale {
+                        eprintln!("Out of date: {}", path.display());
+                    }
+                    eprintln!("Run `build {}` to regenerate them", manifest_path);
+                    process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Error building from manifest '{}': {}", manifest_path, e);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        println!("Building targets from manifest: {}", manifest_path);
+        if let Err(e) = build_from_manifest(manifest_path, build_strict) {
+            eprintln!("Error building from manifest '{}': {}", manifest_path, e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let mut rule_specs: Vec<RuleSpec> = Vec::new();
+    let mut title: Option<String> = None;
     let mut output_json_path = "./layout.json".to_string();
     let mut description = "JIS配列から自作配列への変換".to_string();
     let mut set_from_optional_any = false;
+    let mut shift_variant_policy = ShiftVariantPolicy::Auto;
+    let mut strict = false;
     let mut condition_if_input_source_id: Option<String> = None;
+    let mut application_filter = ApplicationFilter::default();
+    let mut device_presets_path: Option<String> = None;
+    let mut if_devices: Vec<String> = Vec::new();
+    let mut unless_devices: Vec<String> = Vec::new();
+    let mut for_devices: Vec<String> = Vec::new();
+    let mut host_layouts: Vec<HostLayout> = Vec::new();
+    let mut keyboard_type_if = false;
+    let mut kana_input = KanaInput::default();
+    let mut romaji_preset = RomajiPreset::default();
+    let mut romaji_table_path: Option<String> = None;
+    let mut ime_table_path: Option<String> = None;
+    let mut ime_table_format = ImeTableFormat::default();
+    let mut short_code_prefix: Option<String> = None;
+    let mut parameters = Parameters::default();
+    let mut simultaneous_options = SimultaneousSettings::default();
+    let mut merge_into_path: Option<String> = None;
+    let mut profile_name: Option<String> = None;
+    let mut replace_by_description = false;
 
     let mut i = 1;
 
     while i < args.len() {
         match args[i].as_str() {
-            "--input-rs" => {
+            "--input" | "--input-rs" => {
                 if i + 1 < args.len() {
-                    input_rust_file_path = Some(args[i + 1].clone());
+                    rule_specs.push(RuleSpec {
+                        input_path: args[i + 1].clone(),
+                        description: None,
+                        enabled: None,
+                        rule_id: None,
+                    });
                     i += 1;
                 } else {
-                    eprintln!("Error: --input-rs requires a file path");
+                    eprintln!("Error: {} requires a file path", args[i]);
                     process::exit(1);
                 }
             }
@@ -48,16 +380,56 @@ fn main() {
             }
             "--description" => {
                 if i + 1 < args.len() {
-                    description = args[i + 1].clone();
+                    match rule_specs.last_mut() {
+                        Some(spec) => spec.description = Some(args[i + 1].clone()),
+                        None => description = args[i + 1].clone(),
+                    }
                     i += 1;
                 } else {
                     eprintln!("Error: --description requires a value");
                     process::exit(1);
                 }
             }
+            "--disabled" => match rule_specs.last_mut() {
+                Some(spec) => spec.enabled = Some(false),
+                None => {
+                    eprintln!("Error: --disabled must follow an --input");
+                    process::exit(1);
+                }
+            },
+            "--rule-id" => {
+                if i + 1 < args.len() {
+                    match rule_specs.last_mut() {
+                        Some(spec) => spec.rule_id = Some(args[i + 1].clone()),
+                        None => {
+                            eprintln!("Error: --rule-id must follow an --input");
+                            process::exit(1);
+                        }
+                    }
+                    i += 1;
+                } else {
+                    eprintln!("Error: --rule-id requires a value");
+                    process::exit(1);
+                }
+            }
+            "--title" => {
+                if i + 1 < args.len() {
+                    title = Some(args[i + 1].clone());
+                    i += 1;
+                } else {
+                    eprintln!("Error: --title requires a value");
+                    process::exit(1);
+                }
+            }
             "--from-optional-any" => {
                 set_from_optional_any = true;
             }
+            "--no-shift-variants" => {
+                shift_variant_policy = ShiftVariantPolicy::Never;
+            }
+            "--strict" => {
+                strict = true;
+            }
             "--if-input-source-id" => {
                 if i + 1 < args.len() {
                     condition_if_input_source_id = Some(args[i + 1].clone());
@@ -67,20 +439,224 @@ fn main() {
                     process::exit(1);
                 }
             }
+            "--if-bundle-identifier"
+            | "--unless-bundle-identifier"
+            | "--if-file-path"
+            | "--unless-file-path" => {
+                if i + 1 < args.len() {
+                    let list = match args[i].as_str() {
+                        "--if-bundle-identifier" => &mut application_filter.if_bundle_identifiers,
+                        "--unless-bundle-identifier" => {
+                            &mut application_filter.unless_bundle_identifiers
+                        }
+                        "--if-file-path" => &mut application_filter.if_file_paths,
+                        _ => &mut application_filter.unless_file_paths,
+                    };
+                    list.push(args[i + 1].clone());
+                    i += 1;
+                } else {
+                    eprintln!("Error: {} requires a regular expression", args[i]);
+                    process::exit(1);
+                }
+            }
+            "--devices" => {
+                if i + 1 < args.len() {
+                    device_presets_path = Some(args[i + 1].clone());
+                    i += 1;
+                } else {
+                    eprintln!("Error: --devices requires a path to a device presets file");
+                    process::exit(1);
+                }
+            }
+            "--if-device" | "--unless-device" | "--for-device" => {
+                if i + 1 < args.len() {
+                    let names = match args[i].as_str() {
+                        "--if-device" => &mut if_devices,
+                        "--unless-device" => &mut unless_devices,
+                        _ => &mut for_devices,
+                    };
+                    names.push(args[i + 1].clone());
+                    i += 1;
+                } else {
+                    eprintln!("Error: {} requires a device preset name", args[i]);
+                    process::exit(1);
+                }
+            }
+            "--host-layout" => {
+                match args.get(i + 1).and_then(|name| HostLayout::from_name(name)) {
+                    Some(layout) => host_layouts.push(layout),
+                    None => {
+                        eprintln!("Error: --host-layout requires one of jis, ansi or iso");
+                        process::exit(1);
+                    }
+                }
+                i += 1;
+            }
+            "--keyboard-type-if" => {
+                keyboard_type_if = true;
+            }
+            "--kana-input" => {
+                match args.get(i + 1).and_then(|name| KanaInput::from_name(name)) {
+                    Some(input) => kana_input = input,
+                    None => {
+                        eprintln!("Error: --kana-input requires one of romaji or jis_kana");
+                        process::exit(1);
+                    }
+                }
+                i += 1;
+            }
+            "--romaji-scheme" => {
+                match args.get(i + 1).and_then(|name| RomajiPreset::from_name(name)) {
+                    Some(preset) => romaji_preset = preset,
+                    None => {
+                        eprintln!(
+                            "Error: --romaji-scheme requires one of hepburn, kunrei, aquaskk, kotoeri or google"
+                        );
+                        process::exit(1);
+                    }
+                }
+                i += 1;
+            }
+            "--romaji-table" => {
+                if i + 1 < args.len() {
+                    romaji_table_path = Some(args[i + 1].clone());
+                    i += 1;
+                } else {
+                    eprintln!("Error: --romaji-table requires a path to a TSV file");
+                    process::exit(1);
+                }
+            }
+            "--ime-table" => {
+                if i + 1 < args.len() {
+                    ime_table_path = Some(args[i + 1].clone());
+                    i += 1;
+                } else {
+                    eprintln!("Error: --ime-table requires an output file path");
+                    process::exit(1);
+                }
+            }
+            "--ime-table-format" => {
+                match args.get(i + 1).and_then(|name| ImeTableFormat::from_name(name)) {
+                    Some(format) => ime_table_format = format,
+                    None => {
+                        eprintln!(
+                            "Error: --ime-table-format requires one of google, aquaskk or macskk"
+                        );
+                        process::exit(1);
+                    }
+                }
+                i += 1;
+            }
+            "--short-code-prefix" => {
+                if i + 1 < args.len() {
+                    short_code_prefix = Some(args[i + 1].clone());
+                    i += 1;
+                } else {
+                    eprintln!("Error: --short-code-prefix requires a value");
+                    process::exit(1);
+                }
+            }
+            "--to-if-alone-timeout" => {
+                if i + 1 < args.len() {
+                    parameters.to_if_alone_timeout_milliseconds =
+                        Some(parse_milliseconds("--to-if-alone-timeout", &args[i + 1]));
+                    i += 1;
+                } else {
+                    eprintln!("Error: --to-if-alone-timeout requires a value");
+                    process::exit(1);
+                }
+            }
+            "--to-if-held-down-threshold" => {
+                if i + 1 < args.len() {
+                    parameters.to_if_held_down_threshold_milliseconds = Some(parse_milliseconds(
+                        "--to-if-held-down-threshold",
+                        &args[i + 1],
+                    ));
+                    i += 1;
+                } else {
+                    eprintln!("Error: --to-if-held-down-threshold requires a value");
+                    process::exit(1);
+                }
+            }
+            "--to-delayed-action-delay" => {
+                if i + 1 < args.len() {
+                    parameters.to_delayed_action_delay_milliseconds = Some(parse_milliseconds(
+                        "--to-delayed-action-delay",
+                        &args[i + 1],
+                    ));
+                    i += 1;
+                } else {
+                    eprintln!("Error: --to-delayed-action-delay requires a value");
+                    process::exit(1);
+                }
+            }
+            "--simultaneous-threshold" => {
+                if i + 1 < args.len() {
+                    parameters.simultaneous_threshold_milliseconds =
+                        Some(parse_milliseconds("--simultaneous-threshold", &args[i + 1]));
+                    i += 1;
+                } else {
+                    eprintln!("Error: --simultaneous-threshold requires a value");
+                    process::exit(1);
+                }
+            }
+            "--simultaneous-option" => {
+                let Some((key, value)) = args.get(i + 1).and_then(|option| option.split_once('='))
+                else {
+                    eprintln!("Error: --simultaneous-option requires key=value");
+                    process::exit(1);
+                };
+                match simultaneous_options.apply_option(key, value) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        eprintln!("Error: Unknown simultaneous option '{}'", key);
+                        process::exit(1);
+                    }
+                    Err(message) => {
+                        eprintln!("Error: {}", message);
+                        process::exit(1);
+                    }
+                }
+                i += 1;
+            }
+            "--merge-into" => {
+                if i + 1 < args.len() {
+                    merge_into_path = Some(args[i + 1].clone());
+                    i += 1;
+                } else {
+                    eprintln!("Error: --merge-into requires a path to karabiner.json");
+                    process::exit(1);
+                }
+            }
+            "--replace-by-description" => replace_by_description = true,
+            "--profile" => {
+                if i + 1 < args.len() {
+                    profile_name = Some(args[i + 1].clone());
+                    i += 1;
+                } else {
+                    eprintln!("Error: --profile requires a profile name");
+                    process::exit(1);
+                }
+            }
             _ => {}
         }
         i += 1;
     }
-    let source_rust_file = match input_rust_file_path {
-        Some(path) => path,
-        None => {
-            eprintln!("Error: Input Rust file path must be specified with --input-rs <path>");
-            process::exit(1);
-        }
-    };
-    println!("Reading mappings from: {}", source_rust_file);
+    if rule_specs.is_empty() {
+        eprintln!("Error: Input mapping file path must be specified with --input <path>");
+        process::exit(1);
+    }
+    // --input が 1 つだけなら、前後どちらに書かれた --description もそのルールに使う
+    if rule_specs.len() == 1 && rule_specs[0].description.is_none() {
+        rule_specs[0].description = Some(description.clone());
+    }
+    if let Some(ref path) = merge_into_path {
+        output_json_path = path.clone();
+    }
     println!("Outputting to: {}", output_json_path);
-    println!("Using description: {}", description);
+    if let Some(ref t) = title {
+        println!("Writing complex_modifications asset with title: {}", t);
+    }
     println!(
         "Set 'from.modifiers.optional: [\"any\"]': {}",
         set_from_optional_any
@@ -92,39 +668,173 @@ fn main() {
         }
     }
 
-    let parsed_mappings = match parse_mappings_from_rust_file(&source_rust_file) {
-        Ok(mappings) => mappings,
+    if application_filter != ApplicationFilter::default() {
+        println!("Applying frontmost_application conditions: {:?}", application_filter);
+    }
+
+    let device_presets = match device_presets_path {
+        Some(ref path) => match load_device_presets(path) {
+            Ok(presets) => presets,
+            Err(e) => {
+                eprintln!("Error loading device presets from '{}': {}", path, e);
+                process::exit(1);
+            }
+        },
+        None => DevicePresets::default(),
+    };
+    let (device_conditions, device_variants) = match device_presets
+        .conditions(&if_devices, &unless_devices)
+        .and_then(|conditions| Ok((conditions, device_presets.variants(&for_devices)?)))
+    {
+        Ok(devices) => devices,
         Err(e) => {
-            eprintln!(
-                "Error parsing mappings from Rust file '{}': {}",
-                source_rust_file, e
-            );
+            eprintln!("Error: {}", e);
             process::exit(1);
         }
     };
 
-    let mut manipulator_conditions: Option<Vec<ConditionVariant>> = None;
+    // --for-device と --host-layout の組み合わせごとにルールを生成する
+    let rule_variants = combine_variants(
+        &device_variants,
+        &host_layout_variants(&host_layouts, keyboard_type_if),
+    );
+
+    let manipulator_conditions = rule_conditions(
+        &condition_if_input_source_id.into_iter().collect::<Vec<_>>(),
+        &application_filter,
+        device_conditions,
+    );
 
-    if condition_if_input_source_id.is_some() {
-        manipulator_conditions = Some(vec![ConditionVariant::InputSourceIf {
-            input_sources: vec![InputSourceDetail {
-                input_source_id: condition_if_input_source_id,
-            }],
-        }]);
-    };
+    let mut romaji = RomajiScheme::preset(romaji_preset);
+    if let Some(ref path) = romaji_table_path {
+        if let Err(e) = romaji.merge_google_tsv(path) {
+            eprintln!("Error loading romaji table '{}': {}", path, e);
+            process::exit(1);
+        }
+    }
+
+    // 短縮コードはすべての入力で共通にするため、マッピングを先にまとめて読み込む
+    let mut loaded_rules = Vec::new();
+    for spec in rule_specs {
+        println!("Reading mappings from: {}", spec.input_path);
+        match load_mappings(&spec.input_path) {
+            Ok(mappings) => loaded_rules.push((spec, mappings)),
+            Err(e) => {
+                eprintln!(
+                    "Error parsing mappings from '{}': {}",
+                    spec.input_path, e
+                );
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(ref path) = ime_table_path {
+        let all_mappings = loaded_rules.iter().flat_map(|(_, mappings)| mappings);
+        let prefix = short_code_prefix
+            .as_deref()
+            .unwrap_or_else(|| ime_table_format.default_prefix());
+        let short_codes = match assign_short_codes(all_mappings, &romaji, prefix, ime_table_format)
+        {
+            Ok(short_codes) => short_codes,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        };
+        short_codes.apply(&mut romaji);
+        if let Err(e) = short_codes.write(path, ime_table_format) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        println!("Wrote {} short code(s) to {}", short_codes.len(), path);
+    }
 
-    let config: KarabinerFile = generate_karabiner_config(
-        description,
-        &parsed_mappings,
+    let options = RuleOptions {
         set_from_optional_any,
-        manipulator_conditions,
-    );
-    let json_str = match serde_json::to_string_pretty(&config) {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("Failed to serialize to JSON: {}", e);
-            std::process::exit(1);
+        conditions: manipulator_conditions,
+        parameters,
+        simultaneous_options,
+        shift_variant_policy,
+        typing: TypingEnvironment {
+            layout: HostLayout::default(),
+            kana_input,
+            romaji: Rc::new(romaji),
+        },
+    };
+
+    let mut rules = Vec::new();
+    for (spec, parsed_mappings) in loaded_rules {
+        let rule_description = spec.description.unwrap_or_else(|| description.clone());
+        println!("Using description: {}", rule_description);
+
+        let warnings = validate_rule_variants(&parsed_mappings, &options, &rule_variants);
+        for warning in &warnings {
+            eprintln!("{}\n", warning.render());
+        }
+        if strict && !warnings.is_empty() {
+            eprintln!(
+                "Error: {} unknown key name(s) in '{}' (--strict)",
+                warnings.len(),
+                spec.input_path
+            );
+            process::exit(1);
+        }
+
+        for variant in &rule_variants {
+            let mut rule = generate_karabiner_rule(
+                variant.describe(&rule_description),
+                &parsed_mappings,
+                &variant.apply(&options),
+            );
+            rule.enabled = spec.enabled;
+            if merge_into_path.is_some() {
+                rule.make_karabiner_id = Some(
+                    spec.rule_id
+                        .clone()
+                        .unwrap_or_else(|| merge_rule_id(&spec.input_path)),
+                );
+            }
+            rules.push(rule);
+        }
+    }
+
+    let config: KarabinerFile = generate_karabiner_config(title, rules);
+    let json_str = match merge_into_path {
+        Some(ref path) => {
+            println!(
+                "Merging into profile: {}",
+                profile_name.as_deref().unwrap_or("(selected profile)")
+            );
+            match merge_rules_into_karabiner_json(
+                path,
+                profile_name.as_deref(),
+                &config,
+                replace_by_description,
+            ) {
+                Ok(merged) => {
+                    for description in &merged.kept_descriptions {
+                        eprintln!(
+                            "Warning: kept the existing rule '{}' in {}, which was not written by \
+                             make_karabiner; pass --replace-by-description to replace it",
+                            description, path
+                        );
+                    }
+                    merged.json
+                }
+                Err(e) => {
+                    eprintln!("Failed to merge into {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            }
         }
+        None => match serde_json::to_string_pretty(&config) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Failed to serialize to JSON: {}", e);
+                std::process::exit(1);
+            }
+        },
     };
 
     match fs::write(&output_json_path, json_str.as_bytes()) {
@@ -135,3 +845,38 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn environment_flags_are_shared_by_simulate_and_tune_threshold() {
+        let (environment, rest) = split_environment_args(&strings(&[
+            "rules.json",
+            "--input-source-id",
+            "jp.sourceforge.inputmethod.aquaskk.Hiragana",
+            "log.txt",
+            "--from",
+            "30",
+            "--keyboard-type",
+            "jis",
+            "--bundle-identifier",
+            "com.apple.Terminal",
+        ]));
+        assert_eq!(
+            environment.input_source_id.as_deref(),
+            Some("jp.sourceforge.inputmethod.aquaskk.Hiragana")
+        );
+        assert_eq!(environment.keyboard_type.as_deref(), Some("jis"));
+        assert_eq!(
+            environment.bundle_identifier.as_deref(),
+            Some("com.apple.Terminal")
+        );
+        assert_eq!(rest, strings(&["rules.json", "log.txt", "--from", "30"]));
+    }
+}
@@ -0,0 +1,481 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde::Deserialize;
+
+use crate::device_presets::{load_device_presets, DevicePresets};
+use crate::host_layout::HostLayout;
+use crate::ime_table::{assign_short_codes, ImeTableSpec};
+use crate::json_structures::Parameters;
+use crate::kana_input::KanaInput;
+use crate::karabiner_config_generator::{
+    combine_variants, generate_karabiner_config, generate_karabiner_rule, host_layout_variants,
+    rule_conditions, ApplicationFilter, RuleOptions, ShiftVariantPolicy, SimultaneousSettings,
+};
+use crate::key_code_validator::validate_rule_variants;
+use crate::keycode_mapping::TypingEnvironment;
+use crate::mapping_source::{load_mappings, Mapping};
+use crate::romaji_scheme::{RomajiPreset, RomajiScheme};
+
+// 複数の出力ファイルをまとめて再生成するためのマニフェスト (JSON)。
+// ファイル中のパスはマニフェストのあるディレクトリからの相対パスとして解釈する。
+#[derive(Deserialize, Debug)]
+pub struct Manifest {
+    // true なら未知のキー名を警告ではなくエラーとして扱う
+    #[serde(default)]
+    pub strict: bool,
+    // if_devices / unless_devices / for_devices で参照するデバイス定義ファイル
+    #[serde(default)]
+    pub devices: Option<String>,
+    pub targets: Vec<ManifestTarget>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ManifestTarget {
+    pub output: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    pub rules: Vec<ManifestRule>,
+}
+
+// 条件の名前の綴り誤りで条件が黙って無視されないよう、未知の項目はエラーにする
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestRule {
+    pub input: String,
+    pub description: String,
+    #[serde(default)]
+    pub enabled: Option<bool>,
+    #[serde(default)]
+    pub if_input_source_ids: Vec<String>,
+    // frontmost_application_if / unless の対象 (正規表現)
+    #[serde(default)]
+    pub if_bundle_identifiers: Vec<String>,
+    #[serde(default)]
+    pub if_file_paths: Vec<String>,
+    #[serde(default)]
+    pub unless_bundle_identifiers: Vec<String>,
+    #[serde(default)]
+    pub unless_file_paths: Vec<String>,
+    #[serde(default)]
+    pub if_devices: Vec<String>,
+    #[serde(default)]
+    pub unless_devices: Vec<String>,
+    // デバイスごとに device_if を付けたルールを 1 つずつ生成する
+    #[serde(default)]
+    pub for_devices: Vec<String>,
+    // 出力先の配列。複数指定すると keyboard_type_if で出し分けたルールを生成する
+    #[serde(default)]
+    pub host_layouts: Vec<HostLayout>,
+    #[serde(default)]
+    pub keyboard_type_if: bool,
+    #[serde(default)]
+    pub kana_input: KanaInput,
+    #[serde(default)]
+    pub romaji_scheme: RomajiPreset,
+    // プリセットに重ねる Google 日本語入力形式のローマ字テーブル (TSV)
+    #[serde(default)]
+    pub romaji_table: Option<String>,
+    // 仮名の出力を短縮コードにし、そのコードを登録する IME のテーブルを書き出す
+    #[serde(default)]
+    pub ime_table: Option<ImeTableSpec>,
+    #[serde(default)]
+    pub shift_variants: ShiftVariantPolicy,
+    #[serde(default)]
+    pub from_optional_any: bool,
+    // basic.* の閾値。マッピングファイルのセクションごとに上書きできる
+    #[serde(default)]
+    pub parameters: Parameters,
+    // 同時押しの manipulator に付ける simultaneous_options (セクションや同時押しごとの指定が優先)
+    #[serde(default)]
+    pub simultaneous_options: SimultaneousSettings,
+}
+
+impl ManifestRule {
+    pub fn applications(&self) -> ApplicationFilter {
+        ApplicationFilter {
+            if_bundle_identifiers: self.if_bundle_identifiers.clone(),
+            if_file_paths: self.if_file_paths.clone(),
+            unless_bundle_identifiers: self.unless_bundle_identifiers.clone(),
+            unless_file_paths: self.unless_file_paths.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    FileReadError(String),
+    JsonError(String),
+    InvalidMappings(String),
+    UnknownKeyNames(String),
+    DevicePresets(String),
+    RomajiTable(String),
+    ImeTable(String),
+    FileWriteError(String),
+}
+
+impl std::fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ManifestError::FileReadError(s) => write!(f, "File read error: {}", s),
+            ManifestError::JsonError(s) => write!(f, "Manifest error: {}", s),
+            ManifestError::InvalidMappings(s) => write!(f, "Mappings error: {}", s),
+            ManifestError::UnknownKeyNames(s) => write!(f, "Unknown key names: {}", s),
+            ManifestError::DevicePresets(s) => write!(f, "Device presets error: {}", s),
+            ManifestError::RomajiTable(s) => write!(f, "Romaji table error: {}", s),
+            ManifestError::ImeTable(s) => write!(f, "IME table error: {}", s),
+            ManifestError::FileWriteError(s) => write!(f, "File write error: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+pub fn load_manifest(manifest_path: &str) -> Result<Manifest, ManifestError> {
+    let content = fs::read_to_string(manifest_path).map_err(|e| {
+        ManifestError::FileReadError(format!("Failed to read file {}: {}", manifest_path, e))
+    })?;
+    serde_json::from_str(&content)
+        .map_err(|e| ManifestError::JsonError(format!("Failed to parse {}: {}", manifest_path, e)))
+}
+
+fn resolve_path(base_dir: &Path, path: &str) -> PathBuf {
+    base_dir.join(path)
+}
+
+// マニフェストから生成するファイル (Karabiner の JSON と IME のテーブル)
+#[derive(Debug)]
+pub struct ManifestOutput {
+    pub path: PathBuf,
+    pub contents: Vec<u8>,
+}
+
+// 読み込んだルール 1 つ分
+struct LoadedRule {
+    rule: ManifestRule,
+    input_path: String,
+    mappings: Vec<Mapping>,
+    romaji: RomajiScheme,
+}
+
+// 同じ IME のテーブルに書き出すルール (targets と rules の添字)
+struct ImeTableGroup {
+    path: PathBuf,
+    spec: ImeTableSpec,
+    rules: Vec<(usize, usize)>,
+}
+
+// すべてのターゲットを生成する。ファイルには書き込まない。
+pub fn generate_from_manifest(
+    manifest_path: &str,
+    strict: bool,
+) -> Result<Vec<ManifestOutput>, ManifestError> {
+    let manifest = load_manifest(manifest_path)?;
+    let strict = strict || manifest.strict;
+    let base_dir = Path::new(manifest_path)
+        .parent()
+        .unwrap_or_else(|| Path::new("."));
+    let device_presets = match manifest.devices {
+        Some(ref path) => {
+            load_device_presets(&resolve_path(base_dir, path).to_string_lossy())
+                .map_err(|e| ManifestError::DevicePresets(e.to_string()))?
+        }
+        None => DevicePresets::default(),
+    };
+
+    // 短縮コードを割り当てる前に、すべてのルールのマッピングとローマ字の綴りを読み込む
+    let mut targets = Vec::new();
+    for target in manifest.targets {
+        let mut rules = Vec::new();
+        for manifest_rule in target.rules {
+            let input_path = resolve_path(base_dir, &manifest_rule.input)
+                .to_string_lossy()
+                .into_owned();
+            println!("Reading mappings from: {}", input_path);
+            let mappings = load_mappings(&input_path)
+                .map_err(|e| ManifestError::InvalidMappings(format!("{}: {}", input_path, e)))?;
+            let mut romaji = RomajiScheme::preset(manifest_rule.romaji_scheme);
+            if let Some(ref path) = manifest_rule.romaji_table {
+                romaji
+                    .merge_google_tsv(&resolve_path(base_dir, path).to_string_lossy())
+                    .map_err(|e| ManifestError::RomajiTable(e.to_string()))?;
+            }
+            rules.push(LoadedRule {
+                rule: manifest_rule,
+                input_path,
+                mappings,
+                romaji,
+            });
+        }
+        targets.push((target.output, target.title, rules));
+    }
+
+    // 短縮コードは IME のテーブル (出力ファイル) ごとに、そこへ書き出すすべてのルールのマッピングから
+    // まとめて割り当てる。ルールごとに割り当てると、同じコードが別の仮名に使われてしまう。
+    let mut outputs = Vec::new();
+    let mut tables: Vec<ImeTableGroup> = Vec::new();
+    for (target_index, (_, _, rules)) in targets.iter().enumerate() {
+        for (rule_index, loaded) in rules.iter().enumerate() {
+            let Some(ref spec) = loaded.rule.ime_table else {
+                continue;
+            };
+            let path = resolve_path(base_dir, &spec.output);
+            match tables.iter_mut().find(|table| table.path == path) {
+                Some(table) => {
+                    if table.spec.format != spec.format || table.spec.prefix() != spec.prefix() {
+                        return Err(ManifestError::ImeTable(format!(
+                            "rules writing {} use different formats or prefixes",
+                            path.display()
+                        )));
+                    }
+                    table.rules.push((target_index, rule_index));
+                }
+                None => tables.push(ImeTableGroup {
+                    path,
+                    spec: spec.clone(),
+                    rules: vec![(target_index, rule_index)],
+                }),
+            }
+        }
+    }
+    for ImeTableGroup { path, spec, rules } in tables {
+        let (first_target, first_rule) = rules[0];
+        let short_codes = assign_short_codes(
+            rules
+                .iter()
+                .flat_map(|&(target, rule)| &targets[target].2[rule].mappings),
+            &targets[first_target].2[first_rule].romaji,
+            spec.prefix(),
+            spec.format,
+        )
+        .map_err(|e| ManifestError::ImeTable(e.to_string()))?;
+        for &(target, rule) in &rules {
+            short_codes.apply(&mut targets[target].2[rule].romaji);
+        }
+        outputs.push(ManifestOutput {
+            path,
+            contents: short_codes.render(spec.format),
+        });
+    }
+
+    for (output, title, loaded_rules) in targets {
+        let mut rules = Vec::new();
+        for LoadedRule {
+            rule: manifest_rule,
+            input_path,
+            mappings: parsed_mappings,
+            romaji,
+        } in loaded_rules
+        {
+            let device_conditions = device_presets
+                .conditions(&manifest_rule.if_devices, &manifest_rule.unless_devices)
+                .map_err(|e| ManifestError::DevicePresets(e.to_string()))?;
+            let device_variants = device_presets
+                .variants(&manifest_rule.for_devices)
+                .map_err(|e| ManifestError::DevicePresets(e.to_string()))?;
+            let rule_variants = combine_variants(
+                &device_variants,
+                &host_layout_variants(&manifest_rule.host_layouts, manifest_rule.keyboard_type_if),
+            );
+
+            let options = RuleOptions {
+                set_from_optional_any: manifest_rule.from_optional_any,
+                conditions: rule_conditions(
+                    &manifest_rule.if_input_source_ids,
+                    &manifest_rule.applications(),
+                    device_conditions,
+                ),
+                parameters: manifest_rule.parameters,
+                simultaneous_options: manifest_rule.simultaneous_options,
+                shift_variant_policy: manifest_rule.shift_variants,
+                typing: TypingEnvironment {
+                    layout: HostLayout::default(),
+                    kana_input: manifest_rule.kana_input,
+                    romaji: Rc::new(romaji),
+                },
+            };
+            let warnings = validate_rule_variants(&parsed_mappings, &options, &rule_variants);
+            for warning in &warnings {
+                eprintln!("{}\n", warning.render());
+            }
+            if strict && !warnings.is_empty() {
+                return Err(ManifestError::UnknownKeyNames(format!(
+                    "{} unknown key name(s) in {}",
+                    warnings.len(),
+                    input_path
+                )));
+            }
+
+            for variant in &rule_variants {
+                let mut rule = generate_karabiner_rule(
+                    variant.describe(&manifest_rule.description),
+                    &parsed_mappings,
+                    &variant.apply(&options),
+                );
+                rule.enabled = manifest_rule.enabled;
+                rules.push(rule);
+            }
+        }
+
+        let config = generate_karabiner_config(title, rules);
+        let json_str = serde_json::to_string_pretty(&config)
+            .map_err(|e| ManifestError::JsonError(format!("Failed to serialize to JSON: {}", e)))?;
+
+        outputs.push(ManifestOutput {
+            path: resolve_path(base_dir, &output),
+            contents: json_str.into_bytes(),
+        });
+    }
+    Ok(outputs)
+}
+
+pub fn build_from_manifest(manifest_path: &str, strict: bool) -> Result<(), ManifestError> {
+    for output in generate_from_manifest(manifest_path, strict)? {
+        fs::write(&output.path, &output.contents).map_err(|e| {
+            ManifestError::FileWriteError(format!(
+                "Failed to write to {}: {}",
+                output.path.display(),
+                e
+            ))
+        })?;
+        println!("Successfully wrote to {}", output.path.display());
+    }
+    Ok(())
+}
+
+// 生成結果と内容が異なる (または存在しない) 出力ファイル。コミットした出力が古くないかの確認に使う。
+pub fn stale_manifest_outputs(
+    manifest_path: &str,
+    strict: bool,
+) -> Result<Vec<PathBuf>, ManifestError> {
+    Ok(generate_from_manifest(manifest_path, strict)?
+        .into_iter()
+        .filter(|output| fs::read(&output.path).ok().as_deref() != Some(&output.contents[..]))
+        .map(|output| output.path)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo_manifest() -> String {
+        format!("{}/manifest.json", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    // コミットした layout.json などは `build` の結果と一致していなければならない
+    #[test]
+    fn committed_outputs_match_build() {
+        let stale = stale_manifest_outputs(&repo_manifest(), false).unwrap();
+        assert!(
+            stale.is_empty(),
+            "run `cargo run -- build` and commit the result: {:?}",
+            stale
+        );
+    }
+
+    #[test]
+    fn manifest_generates_every_target() {
+        let outputs = generate_from_manifest(&repo_manifest(), false).unwrap();
+        let names: Vec<_> = outputs
+            .iter()
+            .filter_map(|output| output.path.file_name()?.to_str())
+            .collect();
+        assert_eq!(names, ["layout.json", "shingeta.json", "modifier.json"]);
+        for output in &outputs {
+            let file: crate::json_structures::File =
+                serde_json::from_slice(&output.contents).unwrap();
+            assert!(!file.rules.is_empty());
+        }
+    }
+
+    // 同じ IME のテーブルに書き出す 2 つのルールには、重ならない短縮コードを割り当てる
+    #[test]
+    fn short_codes_are_shared_across_rules() {
+        let dir =
+            std::env::temp_dir().join(format!("make_karabiner_manifest_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.json"), r#"{"mappings": [["q", "きゃ"]]}"#).unwrap();
+        fs::write(dir.join("b.json"), r#"{"mappings": [["w", "しゅ"]]}"#).unwrap();
+        let ime_table = r#"{"output": "codes.txt"}"#;
+        fs::write(
+            dir.join("manifest.json"),
+            format!(
+                r#"{{"targets": [
+                    {{"output": "a_out.json", "rules": [{{"input": "a.json", "description": "a", "ime_table": {0}}}]}},
+                    {{"output": "b_out.json", "rules": [{{"input": "b.json", "description": "b", "ime_table": {0}}}]}}
+                ]}}"#,
+                ime_table
+            ),
+        )
+        .unwrap();
+        let outputs = generate_from_manifest(&dir.join("manifest.json").to_string_lossy(), false);
+        fs::remove_dir_all(&dir).unwrap();
+        let outputs = outputs.unwrap();
+
+        let tables: Vec<_> = outputs
+            .iter()
+            .filter(|output| output.path.ends_with("codes.txt"))
+            .collect();
+        assert_eq!(tables.len(), 1);
+        let table = String::from_utf8(tables[0].contents.clone()).unwrap();
+        assert_eq!(table, "qa\tきゃ\nqb\tしゅ\n");
+
+        // それぞれのルールはテーブルと同じコードを打つ
+        let typed = |name: &str| -> Vec<String> {
+            let output = outputs.iter().find(|o| o.path.ends_with(name)).unwrap();
+            let file: crate::json_structures::File =
+                serde_json::from_slice(&output.contents).unwrap();
+            file.rules[0].manipulators[0]
+                .to
+                .iter()
+                .map(|to| to.key_code.as_ref().unwrap().name().to_string())
+                .collect()
+        };
+        assert_eq!(typed("a_out.json"), ["q", "a"]);
+        assert_eq!(typed("b_out.json"), ["q", "b"]);
+    }
+
+    #[test]
+    fn misspelled_rule_keys_are_errors() {
+        let rule = |key: &str| {
+            serde_json::from_str::<ManifestRule>(&format!(
+                r#"{{"input": "a.json", "description": "a", "{}": ["^com\\.example$"]}}"#,
+                key
+            ))
+        };
+        let error = rule("bundle_identifer").unwrap_err().to_string();
+        assert!(
+            error.starts_with("unknown field `bundle_identifer`"),
+            "{}",
+            error
+        );
+        let applications = rule("unless_bundle_identifiers").unwrap().applications();
+        assert_eq!(applications.unless_bundle_identifiers, ["^com\\.example$"]);
+    }
+
+    #[test]
+    fn manifest_writes_title_and_disabled_rules() {
+        let dir =
+            std::env::temp_dir().join(format!("make_karabiner_enabled_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.json"), r#"{"mappings": [["j", "k"]]}"#).unwrap();
+        fs::write(
+            dir.join("manifest.json"),
+            r#"{"targets": [{"output": "out.json", "title": "My layout", "rules": [
+                {"input": "a.json", "description": "on"},
+                {"input": "a.json", "description": "off", "enabled": false}
+            ]}]}"#,
+        )
+        .unwrap();
+        let outputs = generate_from_manifest(&dir.join("manifest.json").to_string_lossy(), false);
+        fs::remove_dir_all(&dir).unwrap();
+        let outputs = outputs.unwrap();
+
+        let value: serde_json::Value = serde_json::from_slice(&outputs[0].contents).unwrap();
+        assert_eq!(value["title"], "My layout");
+        assert!(value["rules"][0].get("enabled").is_none());
+        assert_eq!(value["rules"][1]["enabled"], false);
+    }
+}
@@ -0,0 +1,226 @@
+// マッピング列をセクションに区切る。
+// from が `[layer nav]` のようなエントリはセクションの見出しで、to に `key=value` 形式の
+// オプションを空白区切りで書く。見出し以降のマッピングは次の見出しまでそのセクションに属する。
+//
+//   ("[layer nav]", "trigger=caps_lock mode=hold tap=escape"),
+//   ("h", "left_arrow"),
+//   ("[section base]", "unless_bundle_identifiers=^com\\.apple\\.Terminal$"),
+//
+// どの種類のセクションにも if_bundle_identifiers / unless_bundle_identifiers /
+// if_file_paths / unless_file_paths を (繰り返し) 書ける。同時押しのオプション (key_down_order=strict など) を
+// 書くと、セクション内の同時押しすべてに付く。parameters の項目 (to_if_alone_timeout_milliseconds=200 など) は
+// ルール全体の指定を上書きする。
+// 最初の見出しより前のマッピングは名前のない通常セクションに入る。
+
+use crate::diagnostics::Diagnostic;
+use crate::json_structures::Parameters;
+use crate::karabiner_config_generator::{
+    apply_parameter_option, ApplicationFilter, SimultaneousSettings,
+};
+use crate::mapping_source::Mapping;
+
+// レイヤーの切り替え方
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LayerMode {
+    // トリガーを押している間だけ有効
+    Hold,
+    // トリガーを押すたびに有効・無効を切り替える
+    Toggle,
+}
+
+#[derive(Debug, Clone)]
+pub struct LayerSpec {
+    pub trigger: String,
+    pub mode: LayerMode,
+    // トリガーを単独で押した時の出力 (hold のみ)
+    pub tap: Option<String>,
+    pub variable: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum SectionKind {
+    Plain,
+    Layer(LayerSpec),
+}
+
+#[derive(Debug, Clone)]
+pub struct MappingSection<'a> {
+    pub kind: SectionKind,
+    pub applications: ApplicationFilter,
+    pub parameters: Parameters,
+    pub simultaneous_options: SimultaneousSettings,
+    // 見出しのマッピング。通常セクションの先頭 (見出しなし) では None。
+    pub header: Option<&'a Mapping>,
+    pub mappings: Vec<&'a Mapping>,
+}
+
+pub fn is_section_header(mapping: &Mapping) -> bool {
+    mapping.from.len() > 2 && mapping.from.starts_with('[') && mapping.from.ends_with(']')
+}
+
+// 空白区切りの `key=value` を分解する。context はエラーメッセージに使う。
+pub fn parse_option_tokens<'a>(
+    options: &'a str,
+    context: &str,
+) -> Result<Vec<(&'a str, &'a str)>, String> {
+    options
+        .split_whitespace()
+        .map(|token| {
+            token
+                .split_once('=')
+                .filter(|(key, value)| !key.is_empty() && !value.is_empty())
+                .ok_or_else(|| {
+                    format!(
+                        "Option '{}' in '{}' must be written as key=value",
+                        token, context
+                    )
+                })
+        })
+        .collect()
+}
+
+// どの種類のセクションにも書けるオプション
+#[derive(Debug, Default)]
+struct CommonOptions {
+    applications: ApplicationFilter,
+    parameters: Parameters,
+    simultaneous_options: SimultaneousSettings,
+}
+
+impl CommonOptions {
+    fn apply_option(&mut self, key: &str, value: &str, context: &str) -> Result<bool, String> {
+        if self.applications.apply_option(key, value) {
+            return Ok(true);
+        }
+        let in_context = |message: String| format!("{} in '{}'", message, context);
+        if apply_parameter_option(&mut self.parameters, key, value).map_err(in_context)? {
+            return Ok(true);
+        }
+        self.simultaneous_options
+            .apply_option(key, value)
+            .map_err(in_context)
+    }
+}
+
+fn parse_layer_spec(
+    name: &str,
+    header: &Mapping,
+    common: &mut CommonOptions,
+) -> Result<LayerSpec, String> {
+    let mut trigger = None;
+    let mut mode = LayerMode::Hold;
+    let mut tap = None;
+    let mut variable = format!("layer_{}", name);
+    for (key, value) in parse_option_tokens(&header.to, &header.from)? {
+        match key {
+            "trigger" => trigger = Some(value.to_string()),
+            "mode" => {
+                mode = match value {
+                    "hold" => LayerMode::Hold,
+                    "toggle" => LayerMode::Toggle,
+                    _ => {
+                        return Err(format!(
+                            "Unknown layer mode '{}' in '{}' (expected hold or toggle)",
+                            value, header.from
+                        ))
+                    }
+                }
+            }
+            "tap" => tap = Some(value.to_string()),
+            "variable" => variable = value.to_string(),
+            _ if common.apply_option(key, value, &header.from)? => {}
+            _ => {
+                return Err(format!(
+                    "Unknown layer option '{}' in '{}'",
+                    key, header.from
+                ))
+            }
+        }
+    }
+    let trigger = trigger.ok_or_else(|| format!("Layer '{}' needs a trigger=<key>", name))?;
+    if tap.is_some() && mode == LayerMode::Toggle {
+        return Err(format!(
+            "Layer '{}': tap= is only supported with mode=hold",
+            name
+        ));
+    }
+    Ok(LayerSpec {
+        trigger,
+        mode,
+        tap,
+        variable,
+    })
+}
+
+fn parse_header(header: &Mapping) -> Result<(SectionKind, CommonOptions), String> {
+    let inner = &header.from[1..header.from.len() - 1];
+    let mut words = inner.split_whitespace();
+    let (kind, name) = match (words.next(), words.next(), words.next()) {
+        (Some(kind), Some(name), None) => (kind, name),
+        _ => {
+            return Err(format!(
+                "Section header '{}' must be written as [<kind> <name>]",
+                header.from
+            ))
+        }
+    };
+    let mut common = CommonOptions::default();
+    match kind {
+        "layer" => {
+            let layer = parse_layer_spec(name, header, &mut common)?;
+            Ok((SectionKind::Layer(layer), common))
+        }
+        "section" => {
+            for (key, value) in parse_option_tokens(&header.to, &header.from)? {
+                if !common.apply_option(key, value, &header.from)? {
+                    return Err(format!(
+                        "Unknown section option '{}' in '{}'",
+                        key, header.from
+                    ));
+                }
+            }
+            Ok((SectionKind::Plain, common))
+        }
+        _ => Err(format!(
+            "Unknown section kind '{}' in '{}' (expected layer or section)",
+            kind, header.from
+        )),
+    }
+}
+
+// 見出しの誤りはすべて集めて返す。誤った見出しのセクションは通常セクションとして扱う。
+pub fn split_into_sections(mappings: &[Mapping]) -> (Vec<MappingSection<'_>>, Vec<Diagnostic>) {
+    let mut sections = vec![MappingSection {
+        kind: SectionKind::Plain,
+        applications: ApplicationFilter::default(),
+        parameters: Parameters::default(),
+        simultaneous_options: SimultaneousSettings::default(),
+        header: None,
+        mappings: Vec::new(),
+    }];
+    let mut diagnostics = Vec::new();
+    for mapping in mappings {
+        if !is_section_header(mapping) {
+            if let Some(section) = sections.last_mut() {
+                section.mappings.push(mapping);
+            }
+            continue;
+        }
+        let (kind, common) = match parse_header(mapping) {
+            Ok(parsed) => parsed,
+            Err(message) => {
+                diagnostics.push(Diagnostic::new(message, mapping.location.clone()));
+                (SectionKind::Plain, CommonOptions::default())
+            }
+        };
+        sections.push(MappingSection {
+            kind,
+            applications: common.applications,
+            parameters: common.parameters,
+            simultaneous_options: common.simultaneous_options,
+            header: Some(mapping),
+            mappings: Vec::new(),
+        });
+    }
+    (sections, diagnostics)
+}
@@ -0,0 +1,640 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+use yaml_rust2::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust2::scanner::Marker;
+
+use crate::diagnostics::{Diagnostic, SourceLocation};
+use crate::karabiner_config_generator::{ApplicationFilter, SimultaneousSettings};
+use crate::keycode_mapping::{
+    parse_combo, parse_to_input_string, split_modifier_notation, split_simultaneous_options,
+    TypingEnvironment,
+};
+use crate::mapping_sections::{is_section_header, split_into_sections};
+use crate::rust_mappings_parser::{parse_mappings_from_rust_file, ParseError};
+
+// 1 つのマッピング。location はエラー表示のために元ファイル中の位置を保持する。
+// applications はこのマッピングだけに付ける最前面アプリケーションの条件。
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    pub from: String,
+    pub to: String,
+    pub applications: ApplicationFilter,
+    pub location: Option<SourceLocation>,
+}
+
+// マッピングの入力形式。ファイルの拡張子から選択する。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MappingFormat {
+    Rust,
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl MappingFormat {
+    pub fn from_path(file_path: &str) -> Option<MappingFormat> {
+        match Path::new(file_path).extension()?.to_str()? {
+            "rs" => Some(MappingFormat::Rust),
+            "json" => Some(MappingFormat::Json),
+            "toml" => Some(MappingFormat::Toml),
+            "yaml" | "yml" => Some(MappingFormat::Yaml),
+            _ => None,
+        }
+    }
+}
+
+// JSON/TOML/YAML の 1 エントリの表の形 `{ from = "q", to = "k" }`。`["q", "k"]` とも書ける。
+// `unless_bundle_identifiers = [...]` などの条件も指定できる。条件の名前の綴り誤りで
+// 条件が黙って無視されないよう、未知の項目はエラーにする。
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct MappingTable {
+    from: String,
+    to: String,
+    #[serde(default)]
+    if_bundle_identifiers: Vec<String>,
+    #[serde(default)]
+    if_file_paths: Vec<String>,
+    #[serde(default)]
+    unless_bundle_identifiers: Vec<String>,
+    #[serde(default)]
+    unless_file_paths: Vec<String>,
+}
+
+// 表の形のエントリは表として読み、足りない項目や未知の項目を serde のエラーのまま報告する
+fn parse_entry(value: serde_json::Value) -> Result<(String, String, ApplicationFilter), String> {
+    if !value.is_object() {
+        return serde_json::from_value::<(String, String)>(value)
+            .map(|(from, to)| (from, to, ApplicationFilter::default()))
+            .map_err(|_| {
+                "Mapping entry must be [from, to] or a table with 'from' and 'to' strings"
+                    .to_string()
+            });
+    }
+    let table: MappingTable =
+        serde_json::from_value(value).map_err(|e| format!("Invalid mapping table: {}", e))?;
+    let applications = ApplicationFilter {
+        if_bundle_identifiers: table.if_bundle_identifiers,
+        if_file_paths: table.if_file_paths,
+        unless_bundle_identifiers: table.unless_bundle_identifiers,
+        unless_file_paths: table.unless_file_paths,
+    };
+    Ok((table.from, table.to, applications))
+}
+
+// JSON は各エントリを元の文字列のまま受け取り、その先頭から位置を求める
+#[derive(Deserialize, Debug)]
+struct JsonMappingDocument<'a> {
+    #[serde(borrow)]
+    mappings: Vec<&'a serde_json::value::RawValue>,
+}
+
+// TOML では各エントリの位置も取得できる。形の誤りをエントリごとに報告するため、値のまま受け取る。
+#[derive(Deserialize, Debug)]
+struct TomlMappingDocument {
+    mappings: Vec<toml::Spanned<toml::Value>>,
+}
+
+// 読めたエントリと、読めなかったエントリの診断
+type ParsedEntries = (Vec<Mapping>, Vec<Diagnostic>);
+
+// 1 つのエントリが読めなくても残りを読み続け、誤りをすべて集める
+fn collect_entries(
+    entries: impl IntoIterator<Item = (Result<serde_json::Value, String>, SourceLocation)>,
+) -> ParsedEntries {
+    let mut mappings = Vec::new();
+    let mut diagnostics = Vec::new();
+    for (value, location) in entries {
+        match value.and_then(parse_entry) {
+            Ok((from, to, applications)) => mappings.push(Mapping {
+                from,
+                to,
+                applications,
+                location: Some(location),
+            }),
+            Err(message) => diagnostics.push(Diagnostic::new(message, Some(location))),
+        }
+    }
+    (mappings, diagnostics)
+}
+
+fn parse_json_mappings(file_path: &str, content: &str) -> Result<ParsedEntries, ParseError> {
+    let document: JsonMappingDocument = serde_json::from_str(content).map_err(|e| {
+        let location = (e.line() > 0).then(|| SourceLocation {
+            file: file_path.to_string(),
+            line: e.line(),
+            column: e.column().saturating_sub(1),
+        });
+        ParseError::DeserializeError(Diagnostic::new(e.to_string(), location))
+    })?;
+    Ok(collect_entries(document.mappings.into_iter().map(|raw| {
+        // RawValue は content を借用しているため、ポインタの差がエントリの先頭のオフセットになる
+        let offset = raw.get().as_ptr() as usize - content.as_ptr() as usize;
+        (
+            serde_json::from_str(raw.get()).map_err(|e| e.to_string()),
+            SourceLocation::from_byte_offset(file_path, content, offset),
+        )
+    })))
+}
+
+fn parse_toml_mappings(file_path: &str, content: &str) -> Result<ParsedEntries, ParseError> {
+    let document: TomlMappingDocument = toml::from_str(content).map_err(|e| {
+        let location = e
+            .span()
+            .map(|span| SourceLocation::from_byte_offset(file_path, content, span.start));
+        ParseError::DeserializeError(Diagnostic::new(e.message(), location))
+    })?;
+    Ok(collect_entries(document.mappings.into_iter().map(
+        |spanned| {
+            let location =
+                SourceLocation::from_byte_offset(file_path, content, spanned.span().start);
+            (
+                spanned
+                    .into_inner()
+                    .try_into::<serde_json::Value>()
+                    .map_err(|e| e.to_string()),
+                location,
+            )
+        },
+    )))
+}
+
+// YAML はイベントを読みながら値を組み立て、mappings の各エントリの開始位置を記録する。
+// スカラーはすべて文字列として扱う (エントリの項目はどれも文字列か文字列の配列)。
+fn parse_yaml_mappings(file_path: &str, content: &str) -> Result<ParsedEntries, ParseError> {
+    let location = |mark: &Marker| SourceLocation {
+        file: file_path.to_string(),
+        line: mark.line(),
+        column: mark.col(),
+    };
+    let mut events = YamlEvents::default();
+    Parser::new_from_str(content)
+        .load(&mut events, false)
+        .map_err(|e| {
+            ParseError::DeserializeError(Diagnostic::new(e.info(), Some(location(e.marker()))))
+        })?;
+    let entries = events.mapping_entries().map_err(|(message, mark)| {
+        ParseError::DeserializeError(Diagnostic::new(message, Some(location(&mark))))
+    })?;
+    Ok(collect_entries(
+        entries
+            .into_iter()
+            .map(|(value, mark)| (Ok(value), location(&mark))),
+    ))
+}
+
+#[derive(Default)]
+struct YamlEvents {
+    events: Vec<(Event, Marker)>,
+    position: usize,
+    anchors: HashMap<usize, serde_json::Value>,
+}
+
+impl MarkedEventReceiver for YamlEvents {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        self.events.push((event, mark));
+    }
+}
+
+type YamlError = (String, Marker);
+
+impl YamlEvents {
+    // 読み込みに成功していれば StreamEnd で終わるため、それより先を読むことはない
+    fn next(&mut self) -> Result<(Event, Marker), YamlError> {
+        let (event, mark) = self.events[self.position].clone();
+        if event == Event::StreamEnd {
+            return Err(("Unexpected end of YAML".to_string(), mark));
+        }
+        self.position += 1;
+        Ok((event, mark))
+    }
+
+    fn at_sequence_end(&self) -> bool {
+        self.events[self.position].0 == Event::SequenceEnd
+    }
+
+    // トップレベルの `mappings:` の各要素を、その開始位置とともに返す
+    fn mapping_entries(&mut self) -> Result<Vec<(serde_json::Value, Marker)>, YamlError> {
+        while let (Event::StreamStart | Event::DocumentStart, _) = self.events[self.position] {
+            self.position += 1;
+        }
+        let (event, mark) = self.next()?;
+        let Event::MappingStart(..) = event else {
+            return Err(("Expected a table with 'mappings'".to_string(), mark));
+        };
+        let mut entries = None;
+        loop {
+            let (event, mark) = self.next()?;
+            let key = match event {
+                Event::MappingEnd => break,
+                Event::Scalar(key, ..) => key,
+                _ => return Err(("Expected a string key".to_string(), mark)),
+            };
+            if key != "mappings" {
+                self.value()?;
+                continue;
+            }
+            let (event, mark) = self.next()?;
+            let Event::SequenceStart(..) = event else {
+                return Err(("'mappings' must be a list".to_string(), mark));
+            };
+            let mut list = Vec::new();
+            while !self.at_sequence_end() {
+                // ブロック形式の表は開始位置が最初の : になるため、最初のキーの位置を使う
+                let mark = match (
+                    &self.events[self.position],
+                    self.events.get(self.position + 1),
+                ) {
+                    ((Event::MappingStart(..), _), Some((Event::Scalar(..), key))) => *key,
+                    ((_, mark), _) => *mark,
+                };
+                list.push((self.value()?, mark));
+            }
+            self.position += 1;
+            entries = Some(list);
+        }
+        entries.ok_or_else(|| ("Missing 'mappings'".to_string(), mark))
+    }
+
+    fn value(&mut self) -> Result<serde_json::Value, YamlError> {
+        let (event, mark) = self.next()?;
+        let (value, anchor) = match event {
+            Event::Scalar(value, _, anchor, _) => (serde_json::Value::String(value), anchor),
+            Event::Alias(anchor) => {
+                let value = self.anchors.get(&anchor).cloned();
+                return value.ok_or_else(|| ("Unknown alias".to_string(), mark));
+            }
+            Event::SequenceStart(anchor, _) => {
+                let mut list = Vec::new();
+                while !self.at_sequence_end() {
+                    list.push(self.value()?);
+                }
+                self.position += 1;
+                (serde_json::Value::Array(list), anchor)
+            }
+            Event::MappingStart(anchor, _) => {
+                let mut map = serde_json::Map::new();
+                loop {
+                    let (event, mark) = self.next()?;
+                    match event {
+                        Event::MappingEnd => break,
+                        Event::Scalar(key, ..) => {
+                            let value = self.value()?;
+                            map.insert(key, value);
+                        }
+                        _ => return Err(("Expected a string key".to_string(), mark)),
+                    }
+                }
+                (serde_json::Value::Object(map), anchor)
+            }
+            _ => return Err(("Unexpected YAML event".to_string(), mark)),
+        };
+        if anchor > 0 {
+            self.anchors.insert(anchor, value.clone());
+        }
+        Ok(value)
+    }
+}
+
+// マッピング文字列の構文を検査し、見つかったエラーをすべて返す
+pub fn validate_mappings(mappings: &[Mapping]) -> Vec<Diagnostic> {
+    let (_, mut diagnostics) = split_into_sections(mappings);
+    // combo: の誤りは配列によらないため、既定の環境で解釈する
+    let typing = TypingEnvironment::default();
+    for mapping in mappings.iter().filter(|m| !is_section_header(m)) {
+        let mut error = |message: String| {
+            diagnostics.push(Diagnostic::new(message, mapping.location.clone()));
+        };
+
+        if mapping.from.is_empty() {
+            error("Mapping has an empty 'from' string".to_string());
+        }
+        if mapping.to.is_empty() {
+            error(format!(
+                "Mapping for '{}' has an empty 'to' string",
+                mapping.from
+            ));
+        }
+
+        if let Some(rest) = mapping.from.strip_prefix("simul(") {
+            match rest.strip_suffix(")") {
+                Some(inner) => {
+                    let (keys, options) = split_simultaneous_options(inner);
+                    if keys.split_whitespace().count() < 2 {
+                        error(format!(
+                            "'{}' needs at least two keys separated by spaces",
+                            mapping.from
+                        ));
+                    }
+                    if let Some(options) = options {
+                        if let Err(message) = SimultaneousSettings::parse(options, &mapping.from) {
+                            error(message);
+                        }
+                    }
+                }
+                None => error(format!("Unterminated 'simul(' in '{}'", mapping.from)),
+            }
+        }
+
+        // 閉じていない " があると、後ろの tap(...) や hold(...) まで 1 つの出力として読まれる
+        let has_tap_or_hold = mapping.to.contains("tap(") || mapping.to.contains("hold(");
+        if has_tap_or_hold && mapping.to.matches('"').count() % 2 == 1 {
+            error(format!("Unclosed '\"' in '{}'", mapping.to));
+        }
+
+        let parsed_to = parse_to_input_string(&mapping.to);
+        for target in [
+            &parsed_to.to,
+            &parsed_to.to_if_alone,
+            &parsed_to.to_if_held_down,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            for prefix in ["tap(", "hold("] {
+                if target.starts_with(prefix) && !target.ends_with(')') {
+                    error(format!("Unterminated '{}' in '{}'", prefix, mapping.to));
+                }
+            }
+            if let Some(combo) = target.strip_prefix("combo:") {
+                if let Err(message) = parse_combo(combo, &typing) {
+                    error(message);
+                }
+            }
+            let notation = target.strip_prefix("combo:").unwrap_or(target);
+            if let Some((specs, _)) = split_modifier_notation(notation) {
+                if specs.iter().any(|spec| spec.optional) {
+                    error(format!(
+                        "Optional modifiers ('?') are only allowed in 'from': '{}'",
+                        target
+                    ));
+                }
+            }
+        }
+    }
+    sort_by_location(&mut diagnostics);
+    diagnostics
+}
+
+fn sort_by_location(diagnostics: &mut [Diagnostic]) {
+    diagnostics.sort_by_key(|d| {
+        d.location
+            .as_ref()
+            .map(|l| (l.file.clone(), l.line, l.column))
+    });
+}
+
+pub fn load_mappings(file_path: &str) -> Result<Vec<Mapping>, ParseError> {
+    let format = MappingFormat::from_path(file_path).ok_or_else(|| {
+        ParseError::UnsupportedFormat(format!(
+            "{} (expected a .rs, .json, .toml, .yaml or .yml file)",
+            file_path
+        ))
+    })?;
+
+    // Rust ファイルはパーサ内で検査まで行う
+    if format == MappingFormat::Rust {
+        return parse_mappings_from_rust_file(file_path);
+    }
+
+    let content = fs::read_to_string(file_path).map_err(|e| {
+        ParseError::FileReadError(format!("Failed to read file {}: {}", file_path, e))
+    })?;
+    let (mappings, mut diagnostics) = match format {
+        MappingFormat::Json => parse_json_mappings(file_path, &content)?,
+        MappingFormat::Toml => parse_toml_mappings(file_path, &content)?,
+        MappingFormat::Yaml => parse_yaml_mappings(file_path, &content)?,
+        MappingFormat::Rust => unreachable!(),
+    };
+
+    // 読めなかったエントリがあっても、読めたエントリの検査結果と合わせて報告する
+    diagnostics.extend(validate_mappings(&mappings));
+    sort_by_location(&mut diagnostics);
+    if !diagnostics.is_empty() {
+        return Err(ParseError::InvalidMappingsFormat(diagnostics));
+    }
+    Ok(mappings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, content: &str) -> Result<Vec<Mapping>, ParseError> {
+        let path =
+            std::env::temp_dir().join(format!("make_karabiner_{}_{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        let result = load_mappings(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn line_and_column(location: &Option<SourceLocation>) -> (usize, usize) {
+        let location = location.as_ref().expect("location");
+        (location.line, location.column)
+    }
+
+    fn invalid_locations(result: Result<Vec<Mapping>, ParseError>) -> Vec<(usize, usize)> {
+        match result {
+            Err(ParseError::InvalidMappingsFormat(diagnostics)) => diagnostics
+                .iter()
+                .map(|d| line_and_column(&d.location))
+                .collect(),
+            Err(ParseError::DeserializeError(diagnostic)) => {
+                vec![line_and_column(&diagnostic.location)]
+            }
+            other => panic!("unexpected result: {:?}", other.map(|m| m.len())),
+        }
+    }
+
+    #[test]
+    fn json_entries_carry_their_location() {
+        let content = r#"{
+  "mappings": [
+    ["q", "k"],
+    { "from": "w", "to": "", "unless_bundle_identifiers": ["^com\\.apple\\.Terminal$"] },
+    ["e"]
+  ]
+}"#;
+        // エントリの形の誤りと、読めたエントリの検査の誤りをまとめて示す
+        assert_eq!(
+            invalid_locations(load("shape.json", content)),
+            [(4, 4), (5, 4)]
+        );
+
+        let content = content.replace("    [\"e\"]\n", "    [\"e\", \"r\"]\n");
+        assert_eq!(invalid_locations(load("empty.json", &content)), [(4, 4)]);
+
+        let content = content.replace("\"to\": \"\"", "\"to\": \"s\"");
+        let mappings = load("valid.json", &content).unwrap();
+        let locations: Vec<_> = mappings
+            .iter()
+            .map(|m| line_and_column(&m.location))
+            .collect();
+        assert_eq!(locations, [(3, 4), (4, 4), (5, 4)]);
+        assert_eq!(
+            mappings[1].applications.unless_bundle_identifiers,
+            ["^com\\.apple\\.Terminal$"]
+        );
+    }
+
+    #[test]
+    fn yaml_matches_json_and_reports_locations() {
+        let content = r#"# コメント
+mappings:
+  - [q, k]
+  - from: w
+    to: s
+    unless_bundle_identifiers: &terminal ['^com\.apple\.Terminal$']
+  - from: "simul(j k)"
+    to: escape
+    unless_bundle_identifiers: *terminal
+"#;
+        let yaml = load("layout.yaml", content).unwrap();
+        let json = load(
+            "layout.json",
+            r#"{"mappings": [
+                ["q", "k"],
+                {"from": "w", "to": "s", "unless_bundle_identifiers": ["^com\\.apple\\.Terminal$"]},
+                {"from": "simul(j k)", "to": "escape", "unless_bundle_identifiers": ["^com\\.apple\\.Terminal$"]}
+            ]}"#,
+        )
+        .unwrap();
+        let fields = |mappings: &[Mapping]| -> Vec<(String, String, ApplicationFilter)> {
+            mappings
+                .iter()
+                .map(|m| (m.from.clone(), m.to.clone(), m.applications.clone()))
+                .collect()
+        };
+        assert_eq!(fields(&yaml), fields(&json));
+        let locations: Vec<_> = yaml.iter().map(|m| line_and_column(&m.location)).collect();
+        assert_eq!(locations, [(3, 4), (4, 4), (7, 4)]);
+
+        let empty = content.replace("to: s", "to: ''");
+        assert_eq!(invalid_locations(load("empty.yml", &empty)), [(4, 4)]);
+
+        let shape = content.replace("  - [q, k]", "  - [q]");
+        assert_eq!(invalid_locations(load("shape.yml", &shape)), [(3, 4)]);
+
+        let broken = content.replace("  - [q, k]", "  - [q, k");
+        assert!(matches!(
+            load("broken.yaml", &broken),
+            Err(ParseError::DeserializeError(Diagnostic {
+                location: Some(_),
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn toml_shape_errors_point_at_each_entry() {
+        let content = r#"mappings = [
+  ["q", "k"],
+  ["w"],
+  { from = "e", to = "" },
+  { form = "r", to = "t" },
+]
+"#;
+        assert_eq!(
+            invalid_locations(load("shape.toml", content)),
+            [(3, 2), (4, 2), (5, 2)]
+        );
+    }
+
+    fn fixture_errors(name: &str) -> Vec<(usize, usize, String)> {
+        let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        match load_mappings(&path) {
+            Err(ParseError::InvalidMappingsFormat(diagnostics)) => diagnostics
+                .into_iter()
+                .map(|d| {
+                    let (line, column) = line_and_column(&d.location);
+                    (line, column, d.message)
+                })
+                .collect(),
+            other => panic!("unexpected result: {:?}", other.map(|m| m.len())),
+        }
+    }
+
+    // 読めないエントリ 2 つと検査で見つかる誤り 2 つを、すべて位置とともに報告する
+    #[test]
+    fn every_error_in_a_mapping_file_is_reported() {
+        let shape = "Mapping entry must be [from, to] or a table with 'from' and 'to' strings";
+        let table = "Invalid mapping table: missing field `from`";
+        let empty = "Mapping for 'e' has an empty 'to' string";
+        let order = "Invalid order 'sideways' (expected insensitive, strict or strict_inverse) \
+                     in 'simul(j k; order=sideways)'";
+        let expected = |lines: [usize; 4]| -> Vec<(usize, usize, String)> {
+            lines
+                .into_iter()
+                .zip([shape, empty, order, table])
+                .map(|(line, message)| (line, 4, message.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            fixture_errors("invalid_mappings.json"),
+            expected([4, 5, 6, 7])
+        );
+        assert_eq!(
+            fixture_errors("invalid_mappings.yaml"),
+            expected([4, 5, 7, 9])
+        );
+    }
+
+    #[test]
+    fn to_grammar_errors_are_reported() {
+        let mapping = |to: &str| Mapping {
+            from: "a".to_string(),
+            to: to.to_string(),
+            applications: Default::default(),
+            location: None,
+        };
+        let mappings = [
+            mapping(r#"text:"a b tap(escape)"#),
+            mapping("combo:cmd+hyper+a"),
+            mapping("combo:cmd+"),
+        ];
+        let messages: Vec<String> = validate_mappings(&mappings)
+            .into_iter()
+            .map(|d| d.message)
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                r#"Unclosed '"' in 'text:"a b tap(escape)'"#,
+                "Unknown modifier 'hyper' in 'combo:cmd+hyper+a'",
+                "'combo:cmd+' does not end with a key",
+            ]
+        );
+    }
+
+    #[test]
+    fn misspelled_condition_keys_are_errors() {
+        let content = r#"{"mappings": [
+  {"from": "j", "to": "k", "bundle_identifer": ["^com\\.apple\\.Terminal$"]},
+  {"from": "f", "to": "g", "unless_bundle_identifiers": ["^com\\.apple\\.Terminal$"]}
+]}"#;
+        match load("misspelled.json", content) {
+            Err(ParseError::InvalidMappingsFormat(diagnostics)) => {
+                assert_eq!(diagnostics.len(), 1);
+                assert!(
+                    diagnostics[0]
+                        .message
+                        .starts_with("Invalid mapping table: unknown field `bundle_identifer`"),
+                    "{}",
+                    diagnostics[0].message
+                );
+                assert_eq!(line_and_column(&diagnostics[0].location), (2, 2));
+            }
+            other => panic!("unexpected result: {:?}", other.map(|m| m.len())),
+        }
+        let mappings = load(
+            "conditions.json",
+            r#"{"mappings": [{"from": "f", "to": "g", "unless_file_paths": ["/vim$"]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(mappings[0].applications.unless_file_paths, ["/vim$"]);
+    }
+}
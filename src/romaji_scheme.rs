@@ -0,0 +1,449 @@
+// 仮名をローマ字入力のキー列にする際の綴り。
+// IME ごとに受け付ける綴りが異なる (ゐ を wi と打つと Google 日本語入力では うぃ になる) ため、
+// 既定の表に IME ごとの差分を重ねたプリセットと、Google 日本語入力の書き出した TSV を読み込める。
+
+use std::collections::HashMap;
+use std::fs;
+
+use serde::Deserialize;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RomajiPreset {
+    #[default]
+    Hepburn,
+    Kunrei,
+    Aquaskk,
+    Kotoeri,
+    Google,
+}
+
+impl RomajiPreset {
+    pub fn from_name(name: &str) -> Option<RomajiPreset> {
+        match name {
+            "hepburn" => Some(RomajiPreset::Hepburn),
+            "kunrei" => Some(RomajiPreset::Kunrei),
+            "aquaskk" => Some(RomajiPreset::Aquaskk),
+            "kotoeri" => Some(RomajiPreset::Kotoeri),
+            "google" => Some(RomajiPreset::Google),
+            _ => None,
+        }
+    }
+
+    // 既定の表と異なる綴り
+    fn overrides(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            RomajiPreset::Hepburn => &[],
+            RomajiPreset::Kunrei => &[
+                ("し", "si"),
+                ("ち", "ti"),
+                ("つ", "tu"),
+                ("ふ", "hu"),
+                ("じ", "zi"),
+                ("しゃ", "sya"),
+                ("しゅ", "syu"),
+                ("しょ", "syo"),
+                ("ちゃ", "tya"),
+                ("ちゅ", "tyu"),
+                ("ちぇ", "tye"),
+                ("ちょ", "tyo"),
+                ("じゃ", "zya"),
+                ("じゅ", "zyu"),
+                ("じぇ", "zye"),
+                ("じょ", "zyo"),
+                ("っ", "xtu"),
+            ],
+            RomajiPreset::Aquaskk => &[("っ", "xtu")],
+            RomajiPreset::Kotoeri | RomajiPreset::Google => {
+                &[("ゐ", "wyi"), ("ゑ", "wye"), ("っ", "xtu")]
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RomajiSchemeError {
+    FileReadError(String),
+    InvalidEntry(String),
+}
+
+impl std::fmt::Display for RomajiSchemeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RomajiSchemeError::FileReadError(s) => write!(f, "File read error: {}", s),
+            RomajiSchemeError::InvalidEntry(s) => write!(f, "Invalid romaji table entry: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for RomajiSchemeError {}
+
+// 仮名 (1 文字または拗音などの 2 文字) からローマ字への表
+#[derive(Debug, Clone)]
+pub struct RomajiScheme {
+    table: HashMap<String, String>,
+}
+
+impl Default for RomajiScheme {
+    fn default() -> Self {
+        RomajiScheme::preset(RomajiPreset::default())
+    }
+}
+
+impl RomajiScheme {
+    pub fn preset(preset: RomajiPreset) -> RomajiScheme {
+        let mut table: HashMap<String, String> = hepburn_table()
+            .into_iter()
+            .map(|(kana, romaji)| (kana.to_string(), romaji.to_string()))
+            .collect();
+        for (kana, romaji) in preset.overrides() {
+            table.insert(kana.to_string(), romaji.to_string());
+        }
+        RomajiScheme { table }
+    }
+
+    // 片仮名は対応する平仮名と同じ綴りで打つ (IME 側で片仮名に変換する)
+    pub fn romaji(&self, kana: &str) -> Option<&str> {
+        if let Some(romaji) = self.table.get(kana) {
+            return Some(romaji);
+        }
+        self.table.get(&katakana_to_hiragana(kana)).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, kana: &str, romaji: &str) {
+        self.table.insert(kana.to_string(), romaji.to_string());
+    }
+
+    pub fn spellings(&self) -> impl Iterator<Item = &str> {
+        self.table.values().map(String::as_str)
+    }
+
+    // (仮名, 綴り)
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.table
+            .iter()
+            .map(|(kana, romaji)| (kana.as_str(), romaji.as_str()))
+    }
+
+    // Google 日本語入力の「ローマ字テーブル」を書き出した TSV (入力<TAB>出力[<TAB>次の入力]) を重ねる。
+    // 同じ仮名に複数の入力があれば最も短いものを使う。次の入力が残るエントリ (tt -> っ + t) と、
+    // 英小文字の列でも 1 文字の記号でもない入力はキー列にできないため使わない。
+    pub fn merge_google_tsv(&mut self, file_path: &str) -> Result<(), RomajiSchemeError> {
+        let content = fs::read_to_string(file_path).map_err(|e| {
+            RomajiSchemeError::FileReadError(format!("Failed to read file {}: {}", file_path, e))
+        })?;
+        let mut loaded: HashMap<String, String> = HashMap::new();
+        for (index, line) in content.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let columns: Vec<&str> = line.split('\t').collect();
+            let (input, output) = match columns.as_slice() {
+                [input, output, ..] if !input.is_empty() && !output.is_empty() => (*input, *output),
+                _ => {
+                    return Err(RomajiSchemeError::InvalidEntry(format!(
+                        "{}:{}: expected <input><TAB><output>",
+                        file_path,
+                        index + 1
+                    )))
+                }
+            };
+            let pending = columns.get(2).is_some_and(|next| !next.is_empty());
+            let typeable =
+                input.chars().all(|c| c.is_ascii_lowercase()) || input.chars().count() == 1;
+            if pending || !typeable {
+                continue;
+            }
+            let shorter = loaded
+                .get(output)
+                .is_none_or(|current| input.len() < current.len());
+            if shorter {
+                loaded.insert(output.to_string(), input.to_string());
+            }
+        }
+        self.table.extend(loaded);
+        Ok(())
+    }
+}
+
+// ァ (U+30A1) から ヶ (U+30F6) までは平仮名と同じ並びで 0x60 だけ後ろにある。
+// ヷ ヸ ヹ ヺ (U+30F7〜U+30FA) には対応する平仮名がないため、同じ音の ゔぁ ゔぃ ゔぇ ゔぉ にする。
+pub fn katakana_to_hiragana(kana: &str) -> String {
+    let mut hiragana = String::new();
+    for c in kana.chars() {
+        match c {
+            '\u{30A1}'..='\u{30F6}' => hiragana.push(char::from_u32(c as u32 - 0x60).unwrap_or(c)),
+            'ヷ' => hiragana.push_str("ゔぁ"),
+            'ヸ' => hiragana.push_str("ゔぃ"),
+            'ヹ' => hiragana.push_str("ゔぇ"),
+            'ヺ' => hiragana.push_str("ゔぉ"),
+            other => hiragana.push(other),
+        }
+    }
+    hiragana
+}
+
+// 既定 (ヘボン式) の綴り。IME が別の仮名として解釈する綴り (ぢ の ji など) は避けている。
+fn hepburn_table() -> HashMap<&'static str, &'static str> {
+    HashMap::from([
+        // 清音
+        ("あ", "a"),
+        ("い", "i"),
+        ("う", "u"),
+        ("え", "e"),
+        ("お", "o"),
+        ("か", "ka"),
+        ("き", "ki"),
+        ("く", "ku"),
+        ("け", "ke"),
+        ("こ", "ko"),
+        ("さ", "sa"),
+        ("し", "shi"),
+        ("す", "su"),
+        ("せ", "se"),
+        ("そ", "so"),
+        ("た", "ta"),
+        ("ち", "chi"),
+        ("つ", "tsu"),
+        ("て", "te"),
+        ("と", "to"),
+        ("な", "na"),
+        ("に", "ni"),
+        ("ぬ", "nu"),
+        ("ね", "ne"),
+        ("の", "no"),
+        ("は", "ha"),
+        ("ひ", "hi"),
+        ("ふ", "fu"),
+        ("へ", "he"),
+        ("ほ", "ho"),
+        ("ま", "ma"),
+        ("み", "mi"),
+        ("む", "mu"),
+        ("め", "me"),
+        ("も", "mo"),
+        ("や", "ya"),
+        ("ゆ", "yu"),
+        ("よ", "yo"),
+        ("ら", "ra"),
+        ("り", "ri"),
+        ("る", "ru"),
+        ("れ", "re"),
+        ("ろ", "ro"),
+        ("わ", "wa"),
+        ("ゐ", "wi"),
+        ("ゑ", "we"),
+        ("を", "wo"), // ゐ (wi), ゑ (we) は現代では稀
+        ("ん", "nn"),
+        // 濁音
+        ("が", "ga"),
+        ("ぎ", "gi"),
+        ("ぐ", "gu"),
+        ("げ", "ge"),
+        ("ご", "go"),
+        ("ざ", "za"),
+        ("じ", "ji"),
+        ("ず", "zu"),
+        ("ぜ", "ze"),
+        ("ぞ", "zo"),
+        ("だ", "da"),
+        ("ぢ", "di"),
+        ("づ", "du"),
+        ("で", "de"),
+        ("ど", "do"), // ぢ (di/ji), づ (du/zu)
+        ("ば", "ba"),
+        ("び", "bi"),
+        ("ぶ", "bu"),
+        ("べ", "be"),
+        ("ぼ", "bo"),
+        // 半濁音
+        ("ぱ", "pa"),
+        ("ぴ", "pi"),
+        ("ぷ", "pu"),
+        ("ぺ", "pe"),
+        ("ぽ", "po"),
+        // 拗音 (きゃ行など)
+        ("きゃ", "kya"),
+        ("きゅ", "kyu"),
+        ("きょ", "kyo"),
+        ("しゃ", "sha"),
+        ("しゅ", "shu"),
+        ("しょ", "sho"), // し (shi) ベース
+        ("ちゃ", "cha"),
+        ("ちゅ", "chu"),
+        ("ちぇ", "che"),
+        ("ちょ", "cho"), // ち (chi) ベース
+        ("にゃ", "nya"),
+        ("にゅ", "nyu"),
+        ("にょ", "nyo"),
+        ("ひゃ", "hya"),
+        ("ひゅ", "hyu"),
+        ("ひょ", "hyo"),
+        ("みゃ", "mya"),
+        ("みゅ", "myu"),
+        ("みょ", "myo"),
+        ("りゃ", "rya"),
+        ("りゅ", "ryu"),
+        ("りょ", "ryo"),
+        // 拗音 (ぎゃ行など - 濁音の拗音)
+        ("ぎゃ", "gya"),
+        ("ぎゅ", "gyu"),
+        ("ぎょ", "gyo"),
+        ("じゃ", "ja"),
+        ("じゅ", "ju"),
+        ("じょ", "jo"), // じ (ji) ベース
+        ("ぢゃ", "dya"),
+        ("ぢゅ", "dyu"),
+        ("ぢょ", "dyo"), // ぢ (di) ベース (じゃ、じゅ、じょ との区別)
+        ("びゃ", "bya"),
+        ("びゅ", "byu"),
+        ("びょ", "byo"),
+        // 拗音 (ぴゃ行など - 半濁音の拗音)
+        ("ぴゃ", "pya"),
+        ("ぴゅ", "pyu"),
+        ("ぴょ", "pyo"),
+        // 外来語表記用の組み合わせ
+        ("ふぁ", "fa"),
+        ("ふぃ", "fi"),
+        ("ふぇ", "fe"),
+        ("ふぉ", "fo"),
+        ("ふゅ", "fyu"),
+        ("うぃ", "uxi"),
+        ("うぇ", "uxe"),
+        ("うぉ", "uxo"),
+        ("いぇ", "ye"),
+        ("ゔ", "vu"),
+        ("ゔぁ", "va"),
+        ("ゔぃ", "vi"),
+        ("ゔぇ", "ve"),
+        ("ゔぉ", "vo"),
+        ("ゔゅ", "vyu"),
+        ("てぃ", "texi"),
+        ("でぃ", "dexi"),
+        ("てゅ", "thu"),
+        ("でゅ", "dhu"),
+        ("とぅ", "twu"),
+        ("どぅ", "dwu"),
+        ("つぁ", "tsa"),
+        ("つぃ", "tsi"),
+        ("つぇ", "tse"),
+        ("つぉ", "tso"),
+        ("しぇ", "sye"),
+        ("じぇ", "je"),
+        ("きぇ", "kye"),
+        ("ぎぇ", "gye"),
+        ("にぇ", "nye"),
+        ("ひぇ", "hye"),
+        ("びぇ", "bye"),
+        ("ぴぇ", "pye"),
+        ("みぇ", "mye"),
+        ("りぇ", "rye"),
+        ("くぁ", "kwa"),
+        ("ぐぁ", "gwa"),
+        ("すぃ", "swi"),
+        ("ずぃ", "zwi"),
+        // 小さい仮名を単独で打つ場合
+        ("ぁ", "xa"),
+        ("ぃ", "xi"),
+        ("ぅ", "xu"),
+        ("ぇ", "xe"),
+        ("ぉ", "xo"),
+        ("ゃ", "xya"),
+        ("ゅ", "xyu"),
+        ("ょ", "xyo"),
+        ("ゎ", "xwa"),
+        ("ゕ", "xka"),
+        ("ゖ", "xke"),
+        ("っ", "xtsu"),
+        // 長音記号。キーボードのハイフンと同じになる。
+        // 句読点や全角の記号は host_layout の記号表で処理する。
+        ("ー", "-"),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_hiragana_and_katakana_has_a_hepburn_spelling() {
+        let scheme = RomajiScheme::preset(RomajiPreset::Hepburn);
+        for c in ('\u{3041}'..='\u{3096}').chain('\u{30A1}'..='\u{30FA}') {
+            assert!(
+                scheme.romaji(&c.to_string()).is_some(),
+                "no spelling for {} (U+{:04X})",
+                c,
+                c as u32
+            );
+        }
+    }
+
+    #[test]
+    fn katakana_folds_to_hiragana() {
+        for c in '\u{30A1}'..='\u{30F6}' {
+            let expected = char::from_u32(c as u32 - 0x60).unwrap().to_string();
+            assert_eq!(katakana_to_hiragana(&c.to_string()), expected);
+        }
+        assert_eq!(katakana_to_hiragana("ヷヸヹヺ"), "ゔぁゔぃゔぇゔぉ");
+        assert_eq!(katakana_to_hiragana("ヴァー"), "ゔぁー");
+    }
+
+    #[test]
+    fn katakana_without_hiragana_uses_the_vu_spellings() {
+        let scheme = RomajiScheme::preset(RomajiPreset::Hepburn);
+        assert_eq!(scheme.romaji("ヷ"), Some("va"));
+        assert_eq!(scheme.romaji("ヸ"), Some("vi"));
+        assert_eq!(scheme.romaji("ヹ"), Some("ve"));
+        assert_eq!(scheme.romaji("ヺ"), Some("vo"));
+    }
+
+    fn merge_tsv(name: &str, content: &str) -> Result<RomajiScheme, RomajiSchemeError> {
+        let path =
+            std::env::temp_dir().join(format!("make_karabiner_{}_{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        let mut scheme = RomajiScheme::preset(RomajiPreset::Hepburn);
+        let result = scheme.merge_google_tsv(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        result.map(|()| scheme)
+    }
+
+    #[test]
+    fn tsv_entries_override_the_preset() {
+        let scheme = merge_tsv("override.tsv", "shi\tし\nsi\tし\nltu\tっ\ntt\tっ\tt\n").unwrap();
+        // 同じ仮名には短い綴りを使い、次の入力を待つ行 (3 列目あり) は使わない
+        assert_eq!(scheme.romaji("し"), Some("si"));
+        assert_eq!(scheme.romaji("っ"), Some("ltu"));
+        // 表にない仮名は既定の綴りのまま
+        assert_eq!(scheme.romaji("か"), Some("ka"));
+    }
+
+    #[test]
+    fn tsv_comments_and_blank_lines_are_skipped() {
+        let scheme = merge_tsv("comments.tsv", "# si\tし\n\n   \nzi\tじ\n").unwrap();
+        assert_eq!(scheme.romaji("し"), Some("shi"));
+        assert_eq!(scheme.romaji("じ"), Some("zi"));
+    }
+
+    #[test]
+    fn malformed_tsv_lines_are_errors() {
+        for (name, content, line) in [
+            ("no_tab.tsv", "ka\tか\nkya\n", 2),
+            ("empty_input.tsv", "\tか\n", 1),
+            ("empty_output.tsv", "# comment\nka\t\n", 2),
+        ] {
+            match merge_tsv(name, content) {
+                Err(RomajiSchemeError::InvalidEntry(message)) => {
+                    assert!(
+                        message.ends_with(&format!(
+                            "{}:{}: expected <input><TAB><output>",
+                            name, line
+                        )),
+                        "{}",
+                        message
+                    );
+                }
+                other => panic!("{}: unexpected result {:?}", name, other.err()),
+            }
+        }
+    }
+}
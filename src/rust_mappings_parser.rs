@@ -1,93 +1,276 @@
 use std::fs;
-use syn::{Expr, ExprArray, ExprReference, ExprTuple, File, Ident, Item, Lit};
+use std::path::{Path, PathBuf};
+use syn::spanned::Spanned;
+use syn::{Expr, ExprArray, ExprMacro, ExprTuple, File, Item, Lit, Macro};
+
+use crate::diagnostics::{render_diagnostics, Diagnostic, SourceLocation};
+use crate::karabiner_config_generator::ApplicationFilter;
+use crate::mapping_sections::parse_option_tokens;
+use crate::mapping_source::{validate_mappings, Mapping};
 
 #[derive(Debug)]
 pub enum ParseError {
     FileReadError(String),
-    SynParseError(String),
+    SynError(Diagnostic),
     MappingsNotFound,
-    InvalidMappingsFormat(String),
+    InvalidMappingsFormat(Vec<Diagnostic>),
+    DeserializeError(Diagnostic),
+    UnsupportedFormat(String),
 }
 
 impl std::fmt::Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ParseError::FileReadError(s) => write!(f, "File read error: {}", s),
-            ParseError::SynParseError(s) => write!(f, "Rust code parse error: {}", s),
+            ParseError::SynError(d) => write!(f, "Rust code parse error:\n{}", d.render()),
             ParseError::MappingsNotFound => write!(f, "'MAPPINGS' constant not found"),
-            ParseError::InvalidMappingsFormat(s) => write!(f, "Invalid 'MAPPINGS' format: {}", s),
+            ParseError::InvalidMappingsFormat(ds) => write!(
+                f,
+                "Invalid 'MAPPINGS' format ({} error(s)):\n{}",
+                ds.len(),
+                render_diagnostics(ds)
+            ),
+            ParseError::DeserializeError(d) => {
+                write!(f, "Mapping file parse error:\n{}", d.render())
+            }
+            ParseError::UnsupportedFormat(s) => write!(f, "Unsupported mapping file format: {}", s),
         }
     }
 }
 
 impl std::error::Error for ParseError {}
 
-pub fn parse_mappings_from_rust_file(file_path: &str) -> Result<Vec<(String, String)>, ParseError> {
-    let content = fs::read_to_string(file_path).map_err(|e| {
-        ParseError::FileReadError(format!("Failed to read file {}: {}", file_path, e))
-    })?;
+// 読み込み中のファイル (include! の循環検出にも使う) と、収集したエラー
+struct ParserContext {
+    files: Vec<PathBuf>,
+    diagnostics: Vec<Diagnostic>,
+}
 
-    let ast: File = syn::parse_file(&content).map_err(|e| {
-        ParseError::SynParseError(format!("Failed to parse Rust file {}: {}", file_path, e))
-    })?;
+impl ParserContext {
+    fn current_file(&self) -> &Path {
+        self.files
+            .last()
+            .map(PathBuf::as_path)
+            .unwrap_or(Path::new(""))
+    }
+
+    fn error_at(&mut self, span: proc_macro2::Span, message: impl Into<String>) {
+        let location = SourceLocation::from_span(self.current_file(), span);
+        self.diagnostics
+            .push(Diagnostic::new(message, Some(location)));
+    }
+
+    fn syn_error(&self, e: &syn::Error) -> ParseError {
+        ParseError::SynError(Diagnostic::new(
+            e.to_string(),
+            Some(SourceLocation::from_span(self.current_file(), e.span())),
+        ))
+    }
+
+    fn read_included(&mut self, mac: &Macro) -> Option<(PathBuf, String)> {
+        let relative: syn::LitStr = match mac.parse_body() {
+            Ok(lit) => lit,
+            Err(e) => {
+                self.error_at(
+                    mac.span(),
+                    format!("include! expects a string literal path: {}", e),
+                );
+                return None;
+            }
+        };
+        let path = self
+            .current_file()
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(relative.value());
+        if self.files.contains(&path) {
+            self.error_at(
+                mac.span(),
+                format!("Recursive include! of {}", path.display()),
+            );
+            return None;
+        }
+        match fs::read_to_string(&path) {
+            Ok(content) => Some((path, content)),
+            Err(e) => {
+                self.error_at(
+                    mac.span(),
+                    format!("Failed to read included file {}: {}", path.display(), e),
+                );
+                None
+            }
+        }
+    }
+}
+
+fn is_include_macro(mac: &Macro) -> bool {
+    mac.path.is_ident("include")
+}
 
-    for item in ast.items {
-        if let Item::Const(item_const) = item {
-            if item_const.ident == Ident::new("MAPPINGS", item_const.ident.span()) {
-                if let Expr::Reference(ExprReference { expr: ref_expr, .. }) = *item_const.expr {
-                    if let Expr::Array(ExprArray { elems, .. }) = &*ref_expr {
-                        let mut parse_mappings = Vec::new();
-                        for elem_expl in elems {
-                            if let Expr::Tuple(ExprTuple {
-                                elems: tuple_elems, ..
-                            }) = elem_expl
-                            {
-                                if tuple_elems.len() == 2 {
-                                    let first = tuple_elems.first().unwrap();
-                                    let second = tuple_elems.last().unwrap();
-
-                                    let s1 = match first {
-                                        Expr::Lit(expr_lit) => match &expr_lit.lit {
-                                            Lit::Str(lit_str) => Ok(lit_str.value()),
-                                            _ => Err(ParseError::InvalidMappingsFormat(
-                                                "Tuple element not a string literal".into(),
-                                            )),
-                                        },
-                                        _ => Err(ParseError::InvalidMappingsFormat(
-                                            "Tuple element not a literal".into(),
-                                        )),
-                                    }?;
-                                    let s2 = match second {
-                                        Expr::Lit(expr_lit) => match &expr_lit.lit {
-                                            Lit::Str(lit_str) => Ok(lit_str.value()),
-                                            _ => Err(ParseError::InvalidMappingsFormat(
-                                                "Tuple element not a string literal".into(),
-                                            )),
-                                        },
-                                        _ => Err(ParseError::InvalidMappingsFormat(
-                                            "Tuple element not a literal".into(),
-                                        )),
-                                    }?;
-                                    parse_mappings.push((s1, s2));
-                                } else {
-                                    return Err(ParseError::InvalidMappingsFormat(
-                                        "Tuple does not have 2 elements".into(),
-                                    ));
-                                }
-                            } else {
-                                return Err(ParseError::InvalidMappingsFormat(
-                                    "Array element in not a tuple".into(),
-                                ));
-                            }
+fn parse_string_like_literal(expr: &Expr, ctx: &mut ParserContext) -> Option<String> {
+    match expr {
+        Expr::Lit(expr_lit) => match &expr_lit.lit {
+            // 通常の文字列・raw 文字列・エスケープを含む文字列はいずれも value() で展開される
+            Lit::Str(lit_str) => Some(lit_str.value()),
+            Lit::Char(lit_char) => Some(lit_char.value().to_string()),
+            other => {
+                ctx.error_at(other.span(), "Tuple element not a string or char literal");
+                None
+            }
+        },
+        Expr::Group(group) => parse_string_like_literal(&group.expr, ctx),
+        Expr::Paren(paren) => parse_string_like_literal(&paren.expr, ctx),
+        other => {
+            ctx.error_at(other.span(), "Tuple element not a literal");
+            None
+        }
+    }
+}
+
+fn parse_mapping_options(options: &str, applications: &mut ApplicationFilter) -> Result<(), String> {
+    for (key, value) in parse_option_tokens(options, options)? {
+        if !applications.apply_option(key, value) {
+            return Err(format!("Unknown mapping option '{}'", key));
+        }
+    }
+    Ok(())
+}
+
+fn parse_mappings_expr(expr: &Expr, ctx: &mut ParserContext) -> Result<Vec<Mapping>, ParseError> {
+    match expr {
+        Expr::Reference(reference) => parse_mappings_expr(&reference.expr, ctx),
+        Expr::Group(group) => parse_mappings_expr(&group.expr, ctx),
+        Expr::Paren(paren) => parse_mappings_expr(&paren.expr, ctx),
+        Expr::Macro(ExprMacro { mac, .. }) if is_include_macro(mac) => {
+            let Some((path, content)) = ctx.read_included(mac) else {
+                return Ok(Vec::new());
+            };
+            ctx.files.push(path);
+            let result = match syn::parse_str::<Expr>(&content) {
+                Ok(included) => parse_mappings_expr(&included, ctx),
+                Err(e) => Err(ctx.syn_error(&e)),
+            };
+            ctx.files.pop();
+            result
+        }
+        Expr::Array(ExprArray { elems, .. }) => {
+            let mut parse_mappings = Vec::new();
+            for elem_expl in elems {
+                let Expr::Tuple(ExprTuple {
+                    elems: tuple_elems, ..
+                }) = elem_expl
+                else {
+                    ctx.error_at(elem_expl.span(), "Array element in not a tuple");
+                    continue;
+                };
+                // 3 つ目の要素は省略可能な条件 ("unless_bundle_identifiers=..." など)
+                if tuple_elems.len() != 2 && tuple_elems.len() != 3 {
+                    ctx.error_at(
+                        elem_expl.span(),
+                        format!(
+                            "Tuple does not have 2 or 3 elements (found {})",
+                            tuple_elems.len()
+                        ),
+                    );
+                    continue;
+                }
+                let s1 = parse_string_like_literal(&tuple_elems[0], ctx);
+                let s2 = parse_string_like_literal(&tuple_elems[1], ctx);
+                let mut applications = ApplicationFilter::default();
+                if let Some(options_expr) = tuple_elems.get(2) {
+                    if let Some(options) = parse_string_like_literal(options_expr, ctx) {
+                        if let Err(message) = parse_mapping_options(&options, &mut applications) {
+                            ctx.error_at(options_expr.span(), message);
                         }
-                        return Ok(parse_mappings);
                     }
                 }
-                return Err(ParseError::InvalidMappingsFormat(
-                    "MAPPINGS contant expression in not an array reference `&[...]`".into(),
-                ));
+                if let (Some(from), Some(to)) = (s1, s2) {
+                    parse_mappings.push(Mapping {
+                        from,
+                        to,
+                        applications,
+                        location: Some(SourceLocation::from_span(
+                            ctx.current_file(),
+                            elem_expl.span(),
+                        )),
+                    });
+                }
             }
+            Ok(parse_mappings)
+        }
+        other => {
+            ctx.error_at(
+                other.span(),
+                "MAPPINGS expression is not an array `[...]` or an array reference `&[...]`",
+            );
+            Ok(Vec::new())
         }
     }
-    Err(ParseError::MappingsNotFound)
+}
+
+fn find_mappings_in_items(
+    items: &[Item],
+    ctx: &mut ParserContext,
+) -> Result<Option<Vec<Mapping>>, ParseError> {
+    for item in items {
+        match item {
+            Item::Const(item_const) if item_const.ident == "MAPPINGS" => {
+                return parse_mappings_expr(&item_const.expr, ctx).map(Some);
+            }
+            Item::Static(item_static) if item_static.ident == "MAPPINGS" => {
+                return parse_mappings_expr(&item_static.expr, ctx).map(Some);
+            }
+            Item::Mod(item_mod) => {
+                if let Some((_, content)) = &item_mod.content {
+                    if let Some(found) = find_mappings_in_items(content, ctx)? {
+                        return Ok(Some(found));
+                    }
+                }
+            }
+            Item::Macro(item_macro) if is_include_macro(&item_macro.mac) => {
+                let Some((path, content)) = ctx.read_included(&item_macro.mac) else {
+                    continue;
+                };
+                ctx.files.push(path);
+                let found = match syn::parse_file(&content) {
+                    Ok(ast) => find_mappings_in_items(&ast.items, ctx),
+                    Err(e) => Err(ctx.syn_error(&e)),
+                };
+                ctx.files.pop();
+                if let Some(found) = found? {
+                    return Ok(Some(found));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+pub fn parse_mappings_from_rust_file(file_path: &str) -> Result<Vec<Mapping>, ParseError> {
+    let content = fs::read_to_string(file_path).map_err(|e| {
+        ParseError::FileReadError(format!("Failed to read file {}: {}", file_path, e))
+    })?;
+
+    let mut ctx = ParserContext {
+        files: vec![PathBuf::from(file_path)],
+        diagnostics: Vec::new(),
+    };
+
+    let ast: File = syn::parse_file(&content).map_err(|e| ctx.syn_error(&e))?;
+
+    let found = find_mappings_in_items(&ast.items, &mut ctx)?;
+    // 構文として読めたエントリの検査結果もまとめて報告する
+    if let Some(mappings) = &found {
+        ctx.diagnostics.extend(validate_mappings(mappings));
+    }
+    if !ctx.diagnostics.is_empty() {
+        ctx.diagnostics.sort_by_key(|d| {
+            d.location
+                .as_ref()
+                .map(|l| (l.file.clone(), l.line, l.column))
+        });
+        return Err(ParseError::InvalidMappingsFormat(ctx.diagnostics));
+    }
+    found.ok_or(ParseError::MappingsNotFound)
 }